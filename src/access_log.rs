@@ -0,0 +1,146 @@
+use std::time::Instant;
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::{self, HeaderName, HeaderValue},
+    middleware::Next,
+    web::Data,
+    Error, HttpMessage,
+};
+use log::info;
+
+use crate::{logging::ACCESS_TARGET, State};
+
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+/// Longer IDs from clients are replaced, so they can't flood the logs.
+const MAX_REQUEST_ID_LEN: usize = 128;
+const REQUEST_ID_LENGTH: usize = 20;
+
+/// Identifies a request in the logs. Taken from the X-Request-Id header if the client sent one.
+#[derive(Clone)]
+pub struct RequestId(pub String);
+
+/// Assigns every request an ID, returns it in the X-Request-Id header and logs the request.
+pub async fn log_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let id = request_id(&req);
+    req.extensions_mut().insert(RequestId(id.clone()));
+    let enabled = req
+        .app_data::<Data<State>>()
+        .is_none_or(|state| state.config.load().log.access_log);
+    // Taken now, since the request isn't handed back if a middleware fails
+    let mut line = AccessLine {
+        start: Instant::now(),
+        method: req.method().to_string(),
+        path: req.path().to_string(),
+        client: req.peer_addr().map(|addr| addr.ip().to_string()),
+        status: 0,
+        bytes: None,
+        key: None,
+    };
+
+    let mut res = match next.call(req).await {
+        Ok(res) => res,
+        Err(err) => {
+            if enabled {
+                line.status = err.as_response_error().status_code().as_u16();
+                line.log(&id);
+            }
+            return Err(err);
+        }
+    };
+
+    if let Ok(value) = HeaderValue::from_str(&id) {
+        res.headers_mut().insert(REQUEST_ID_HEADER, value);
+    }
+
+    if enabled {
+        line.status = res.status().as_u16();
+        if let BodySize::Sized(size) = res.response().body().size() {
+            line.bytes = Some(size);
+        }
+        // Either the key that was requested, or the one that was created
+        line.key = res
+            .request()
+            .match_info()
+            .get("key")
+            .or_else(|| {
+                res.headers()
+                    .get(header::LOCATION)
+                    .and_then(|location| location.to_str().ok())
+            })
+            .map(str::to_string);
+        line.log(&id);
+    }
+    Ok(res)
+}
+
+struct AccessLine {
+    start: Instant,
+    method: String,
+    path: String,
+    client: Option<String>,
+    status: u16,
+    bytes: Option<u64>,
+    key: Option<String>,
+}
+
+impl AccessLine {
+    fn log(&self, id: &str) {
+        let duration = self.start.elapsed().as_micros() as f64 / 1000.0;
+        info!(
+            target: ACCESS_TARGET,
+            request_id = id,
+            method = self.method.as_str(),
+            path = self.path.as_str(),
+            status = self.status,
+            bytes = self.bytes,
+            duration_ms = duration,
+            client = self.client.as_deref(),
+            key = self.key.as_deref();
+            "{} \"{} {}\" {} {} {:.1}ms key={} id={}",
+            self.client.as_deref().unwrap_or("-"),
+            self.method,
+            self.path,
+            self.status,
+            self.bytes.map_or("-".to_string(), |bytes| bytes.to_string()),
+            duration,
+            self.key.as_deref().unwrap_or("-"),
+            id
+        );
+    }
+}
+
+/// The client's request ID if it's usable, or a new one.
+fn request_id(req: &ServiceRequest) -> String {
+    req.headers()
+        .get(REQUEST_ID_HEADER)
+        .and_then(|value| value.to_str().ok())
+        .filter(|id| is_valid_request_id(id))
+        .map(str::to_string)
+        .unwrap_or_else(|| {
+            random_string::generate(REQUEST_ID_LENGTH, random_string::charsets::ALPHANUMERIC)
+        })
+}
+
+fn is_valid_request_id(id: &str) -> bool {
+    !id.is_empty()
+        && id.len() <= MAX_REQUEST_ID_LEN
+        && id.bytes().all(|byte| byte.is_ascii_graphic())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn request_id_test() {
+        assert!(is_valid_request_id("f47ac10b-58cc-4372-a567-0e02b2c3d479"));
+        assert!(!is_valid_request_id(""));
+        assert!(!is_valid_request_id("has spaces"));
+        assert!(!is_valid_request_id(&"a".repeat(MAX_REQUEST_ID_LEN + 1)));
+    }
+}
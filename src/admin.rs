@@ -0,0 +1,163 @@
+use actix_web::{
+    error::{ErrorBadRequest, ErrorInternalServerError, ErrorNotFound},
+    get,
+    web::{Data, Path, Query},
+    Error, HttpRequest, HttpResponse, Responder,
+};
+use serde::Serialize;
+
+use crate::{
+    auth,
+    db::{self, query::ContentQuery, Content, ContentStats},
+    storage, State,
+};
+
+/// A page of content matching the query, see [`ContentQuery`] for the parameters.
+#[get("/admin/content")]
+pub async fn list_content(
+    state: Data<State>,
+    req: HttpRequest,
+    query: Query<ContentQuery>,
+) -> Result<impl Responder, Error> {
+    auth::authorize(&req, &state.config.load().auth)?;
+    let query = query.into_inner();
+    query.validate().map_err(ErrorBadRequest)?;
+
+    let (limit, offset) = (query.limit(), query.offset);
+    let (content, total) = db::search_content_info(&state.metadata, query)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    Ok(HttpResponse::Ok().json(ContentList {
+        total,
+        limit,
+        offset,
+        content: content.iter().map(ContentInfo::from).collect(),
+    }))
+}
+
+/// Everything known about a piece of content, combined from the database and storage.
+#[get("/admin/content/{key}")]
+pub async fn content_details(
+    state: Data<State>,
+    req: HttpRequest,
+    key: Path<String>,
+) -> Result<impl Responder, Error> {
+    auth::authorize(&req, &state.config.load().auth)?;
+
+    let content = match db::get_content_info(&state.metadata, key.clone()).await {
+        Ok(Some(content)) => content,
+        Ok(None) => return Err(ErrorNotFound("Invalid path")),
+        Err(err) => return Err(ErrorInternalServerError(err)),
+    };
+    let mut stats = db::get_stats(&state.metadata, key.clone())
+        .await
+        .map_err(ErrorInternalServerError)?
+        .unwrap_or_default();
+    if let Some(pending) = state.stats.get(&key) {
+        stats.add(&pending);
+    }
+
+    // Some fields are only stored alongside the content
+    let stored = storage::get_content(&state.storage, key.clone(), true).await;
+    let (modifiable, storage_error) = match stored {
+        Ok(stored) => (Some(stored.modifiable), None),
+        Err(err) => (None, Some(err.to_string())),
+    };
+
+    Ok(HttpResponse::Ok().json(ContentDetails {
+        info: ContentInfo::from(&content),
+        modifiable,
+        stats,
+        storage_error,
+    }))
+}
+
+#[derive(Serialize)]
+struct ContentList<'a> {
+    total: u64,
+    limit: u64,
+    offset: u64,
+    content: Vec<ContentInfo<'a>>,
+}
+
+/// Content's metadata, leaving out secrets like its password hash.
+#[derive(Serialize)]
+struct ContentInfo<'a> {
+    key: &'a str,
+    content_type: &'a str,
+    content_encoding: &'a str,
+    content_length: usize,
+    backend_id: &'a str,
+    last_modified: i64,
+    expiry: Option<i64>,
+    max_views: Option<u32>,
+    views: u32,
+    password_protected: bool,
+}
+
+impl<'a> From<&'a Content> for ContentInfo<'a> {
+    fn from(content: &'a Content) -> Self {
+        Self {
+            key: &content.key,
+            content_type: &content.content_type,
+            content_encoding: &content.content_encoding,
+            content_length: content.content_length,
+            backend_id: &content.backend_id,
+            last_modified: content.last_modified,
+            expiry: content.expiry,
+            max_views: content.max_views,
+            views: content.views,
+            password_protected: content.password_hash.is_some(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ContentDetails<'a> {
+    #[serde(flatten)]
+    info: ContentInfo<'a>,
+    /// None if the content couldn't be read from storage
+    modifiable: Option<bool>,
+    stats: ContentStats,
+    storage_error: Option<String>,
+}
+
+/// Stats of all content combined, including those that haven't been written yet.
+#[get("/admin/stats")]
+pub async fn total_stats(state: Data<State>, req: HttpRequest) -> Result<impl Responder, Error> {
+    auth::authorize(&req, &state.config.load().auth)?;
+
+    let mut total = db::total_stats(&state.metadata)
+        .await
+        .map_err(ErrorInternalServerError)?;
+    let pending = state.stats.total();
+    total.views += pending.views;
+    total.bytes_served += pending.bytes_served;
+    Ok(HttpResponse::Ok().json(total))
+}
+
+#[get("/admin/stats/{key}")]
+pub async fn content_stats(
+    state: Data<State>,
+    req: HttpRequest,
+    key: Path<String>,
+) -> Result<impl Responder, Error> {
+    auth::authorize(&req, &state.config.load().auth)?;
+
+    let mut stats = match db::get_stats(&state.metadata, key.clone()).await {
+        Ok(Some(stats)) => stats,
+        Ok(None) => return Err(ErrorNotFound("Invalid path")),
+        Err(err) => return Err(ErrorInternalServerError(err)),
+    };
+    if let Some(pending) = state.stats.get(&key) {
+        stats.add(&pending);
+    }
+    Ok(HttpResponse::Ok().json(StatsResponse { key: &key, stats }))
+}
+
+#[derive(Serialize)]
+struct StatsResponse<'a> {
+    key: &'a str,
+    #[serde(flatten)]
+    stats: ContentStats,
+}
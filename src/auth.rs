@@ -0,0 +1,93 @@
+use actix_web::{
+    error::ErrorUnauthorized,
+    http::header::{HeaderName, HeaderValue},
+    Error, HttpRequest,
+};
+use subtle::ConstantTimeEq;
+
+use crate::{config::AuthConfig, tls::ClientCert};
+
+pub const API_KEY_HEADER: HeaderName = HeaderName::from_static("bitbin-api-key");
+pub const CLIENT_CERT_MODES: &[&str] = &["off", "accepted", "required"];
+
+/// Checks that the client sent one of the configured API keys and a verified client certificate,
+/// as far as `auth.client_cert` asks for them.
+pub fn authorize(req: &HttpRequest, config: &AuthConfig) -> Result<(), Error> {
+    check(
+        config,
+        req.conn_data::<ClientCert>().is_some(),
+        req.headers().get(API_KEY_HEADER),
+    )
+}
+
+fn check(config: &AuthConfig, has_cert: bool, key: Option<&HeaderValue>) -> Result<(), Error> {
+    match config.client_cert.as_str() {
+        "accepted" if has_cert => return Ok(()),
+        "required" if !has_cert => {
+            return Err(ErrorUnauthorized("A client certificate is required"))
+        }
+        "required" if config.api_keys.is_empty() => return Ok(()),
+        _ => {}
+    }
+    let Some(key) = key else {
+        return Err(ErrorUnauthorized("An API key is required"));
+    };
+    // Compared in constant time, so keys can't be guessed one byte at a time
+    let valid = config
+        .api_keys
+        .iter()
+        .filter(|api_key| !api_key.is_empty())
+        .any(|api_key| bool::from(api_key.as_bytes().ct_eq(key.as_bytes())));
+    if !valid {
+        return Err(ErrorUnauthorized("Invalid API key"));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn authorize_test() {
+        let config = AuthConfig {
+            api_keys: vec!["secret".to_string()],
+            ..Default::default()
+        };
+        let req = |key: &str| {
+            TestRequest::default()
+                .insert_header((API_KEY_HEADER, key))
+                .to_http_request()
+        };
+
+        assert!(authorize(&req("secret"), &config).is_ok());
+        assert!(authorize(&req("secret2"), &config).is_err());
+        assert!(authorize(&req(""), &config).is_err());
+        assert!(authorize(&TestRequest::default().to_http_request(), &config).is_err());
+    }
+
+    #[test]
+    fn client_cert_test() {
+        let secret = HeaderValue::from_static("secret");
+        let mut config = AuthConfig {
+            api_keys: vec!["secret".to_string()],
+            ..Default::default()
+        };
+        assert!(check(&config, true, None).is_err());
+
+        config.client_cert = "accepted".to_string();
+        assert!(check(&config, true, None).is_ok());
+        assert!(check(&config, false, Some(&secret)).is_ok());
+        assert!(check(&config, false, None).is_err());
+
+        config.client_cert = "required".to_string();
+        assert!(check(&config, true, Some(&secret)).is_ok());
+        assert!(check(&config, false, Some(&secret)).is_err());
+        assert!(check(&config, true, None).is_err());
+
+        config.api_keys.clear();
+        assert!(check(&config, true, None).is_ok());
+        assert!(check(&config, false, None).is_err());
+    }
+}
@@ -0,0 +1,43 @@
+use std::path::PathBuf;
+
+use clap::{Parser, Subcommand};
+
+/// The default config file, which is optional unless another one is given.
+pub const DEFAULT_CONFIG_PATH: &str = "config.toml";
+
+#[derive(Parser)]
+#[command(version, about)]
+pub struct Cli {
+    /// The config file to load [default: config.toml]
+    #[arg(long, short, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// The directory relative storage and database paths are resolved against
+    #[arg(long, value_name = "DIR")]
+    pub data_dir: Option<PathBuf>,
+
+    /// Print the effective configuration as TOML and exit
+    #[arg(long)]
+    pub print_config: bool,
+
+    #[command(subcommand)]
+    pub command: Option<Command>,
+}
+
+#[derive(Subcommand)]
+pub enum Command {
+    /// Re-encrypt all content with the active key, so old keys can be removed
+    Reencrypt,
+
+    /// Check that the database and storage agree with each other.
+    /// Exits with a non-zero code if there are problems left
+    Fsck {
+        /// Fix any problems found. Damaged files are quarantined
+        #[arg(long)]
+        repair: bool,
+
+        /// Delete damaged files instead of quarantining them
+        #[arg(long, requires = "repair")]
+        delete: bool,
+    },
+}
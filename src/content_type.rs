@@ -0,0 +1,232 @@
+use actix_web::{error::ErrorUnsupportedMediaType, Error};
+
+use crate::config::ContentConfig;
+
+/// What content with a type that isn't allowed is stored as.
+pub const FALLBACK_TYPE: &str = "application/octet-stream";
+/// How much of the content [`sniff`] looks at.
+pub const SNIFF_LEN: usize = 512;
+
+/// Types browsers can run scripts in when they're opened, rather than just display.
+const ACTIVE_TYPES: &[&str] = &[
+    "text/html",
+    "application/xhtml+xml",
+    "image/svg+xml",
+    "text/xml",
+    "application/xml",
+    "text/xsl",
+    "text/javascript",
+    "application/javascript",
+    "application/ecmascript",
+    "text/ecmascript",
+];
+
+/// Magic bytes of binary formats, and where they start.
+const SIGNATURES: &[(usize, &[u8], &str)] = &[
+    (0, b"\x89PNG\r\n\x1a\n", "image/png"),
+    (0, b"\xff\xd8\xff", "image/jpeg"),
+    (0, b"GIF87a", "image/gif"),
+    (0, b"GIF89a", "image/gif"),
+    (8, b"WEBP", "image/webp"),
+    (4, b"ftyp", "video/mp4"),
+    (0, b"\x1a\x45\xdf\xa3", "video/webm"),
+    (0, b"OggS", "audio/ogg"),
+    (0, b"ID3", "audio/mpeg"),
+    (0, b"%PDF-", "application/pdf"),
+    (0, b"PK\x03\x04", "application/zip"),
+    (0, b"\x1f\x8b", "application/gzip"),
+    (0, b"MZ", "application/x-msdownload"),
+    (0, b"\x7fELF", "application/x-executable"),
+];
+
+/// Tags that make browsers treat a document as HTML, see the WHATWG MIME sniffing spec.
+const HTML_TAGS: &[&[u8]] = &[
+    b"<!doctype html",
+    b"<html",
+    b"<head",
+    b"<body",
+    b"<script",
+    b"<iframe",
+    b"<style",
+    b"<title",
+    b"<!--",
+];
+
+/// Guesses the type of content from its first [`SNIFF_LEN`] bytes, for the formats we care about.
+pub fn sniff(content: &[u8]) -> Option<&'static str> {
+    let content = &content[..content.len().min(SNIFF_LEN)];
+    for (offset, magic, content_type) in SIGNATURES {
+        if content.get(*offset..offset + magic.len()) == Some(*magic) {
+            return Some(content_type);
+        }
+    }
+
+    let text = content.strip_prefix(b"\xef\xbb\xbf").unwrap_or(content);
+    let start = text.iter().position(|b| !b.is_ascii_whitespace())?;
+    let text = text[start..].to_ascii_lowercase();
+    if text.starts_with(b"<svg") {
+        return Some("image/svg+xml");
+    }
+    if text.starts_with(b"<?xml") {
+        let is_svg = text.windows(4).any(|window| window == b"<svg");
+        return Some(if is_svg {
+            "image/svg+xml"
+        } else {
+            "application/xml"
+        });
+    }
+    HTML_TAGS
+        .iter()
+        .any(|tag| {
+            // The tag has to end, so e.g. <header> isn't <head>
+            text.starts_with(tag)
+                && text
+                    .get(tag.len())
+                    .is_none_or(|&b| b == b'>' || b.is_ascii_whitespace() || tag == b"<!--")
+        })
+        .then_some("text/html")
+}
+
+/// Whether browsers could run scripts from content of this type if it was opened directly.
+pub fn is_active(content_type: &str) -> bool {
+    let content_type = content_type
+        .split(';')
+        .next()
+        .unwrap_or_default()
+        .trim()
+        .to_ascii_lowercase();
+    ACTIVE_TYPES.contains(&content_type.as_str()) || content_type.ends_with("+xml")
+}
+
+/// Checks an uploaded content type against the allow and deny lists, returning the type to store.
+/// Types that aren't allowed become [`FALLBACK_TYPE`], which can be denied to reject them instead.
+pub fn check(config: &ContentConfig, content_type: &str) -> Result<String, Error> {
+    let allowed =
+        config.allowed_types.is_empty() || matches_any(&config.allowed_types, content_type);
+    let content_type = if allowed { content_type } else { FALLBACK_TYPE };
+    if matches_any(&config.denied_types, content_type) {
+        return Err(ErrorUnsupportedMediaType("Content type not allowed"));
+    }
+    Ok(content_type.to_string())
+}
+
+/// The max size of content with `content_type` in MB, from the first matching
+/// `content.type_maxsize` entry or `content.maxsize`.
+pub fn max_size(config: &ContentConfig, content_type: &str) -> usize {
+    config
+        .type_maxsize
+        .iter()
+        .filter_map(|entry| parse_size_limit(entry))
+        .find(|(pattern, _)| matches(pattern, content_type))
+        .map_or(config.maxsize, |(_, max_size)| max_size)
+}
+
+/// Parses a `pattern=MB` entry of `content.type_maxsize`.
+pub fn parse_size_limit(entry: &str) -> Option<(&str, usize)> {
+    let (pattern, max_size) = entry.split_once('=')?;
+    let pattern = pattern.trim();
+    let max_size = max_size.trim().parse().ok()?;
+    (is_valid_pattern(pattern) && max_size > 0).then_some((pattern, max_size))
+}
+
+/// Whether `pattern` is an exact type like `image/png`, a whole group like `image/*`, or `*`.
+pub fn is_valid_pattern(pattern: &str) -> bool {
+    if pattern == "*" || pattern == "*/*" {
+        return true;
+    }
+    let group = pattern.strip_suffix("/*").unwrap_or(pattern);
+    !group.is_empty() && !group.contains('*')
+}
+
+fn matches_any(patterns: &[String], content_type: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| matches(pattern, content_type))
+}
+
+/// Types are compared case-insensitively, as they're case-insensitive in HTTP.
+fn matches(pattern: &str, content_type: &str) -> bool {
+    if pattern == "*" || pattern == "*/*" {
+        return true;
+    }
+    match pattern.strip_suffix('*') {
+        Some(group) => content_type
+            .get(..group.len())
+            .is_some_and(|prefix| prefix.eq_ignore_ascii_case(group)),
+        None => pattern.eq_ignore_ascii_case(content_type),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn check_test() {
+        let mut config = ContentConfig {
+            denied_types: vec!["application/x-msdownload".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(check(&config, "text/plain").unwrap(), "text/plain");
+        assert!(check(&config, "Application/X-MSDownload").is_err());
+
+        config.allowed_types = vec!["text/*".to_string(), "image/png".to_string()];
+        assert_eq!(check(&config, "TEXT/html").unwrap(), "TEXT/html");
+        assert_eq!(check(&config, "image/png").unwrap(), "image/png");
+        assert_eq!(check(&config, "image/gif").unwrap(), FALLBACK_TYPE);
+        assert_eq!(check(&config, "textual/x").unwrap(), FALLBACK_TYPE);
+
+        config.denied_types.push(FALLBACK_TYPE.to_string());
+        assert!(check(&config, "image/gif").is_err());
+        assert!(check(&config, "text/plain").is_ok());
+    }
+
+    #[test]
+    fn max_size_test() {
+        let config = ContentConfig {
+            maxsize: 10,
+            type_maxsize: vec!["image/png=5".to_string(), "image/*=2".to_string()],
+            ..Default::default()
+        };
+        assert_eq!(max_size(&config, "image/png"), 5);
+        assert_eq!(max_size(&config, "image/gif"), 2);
+        assert_eq!(max_size(&config, "text/plain"), 10);
+
+        assert_eq!(parse_size_limit(" video/* = 50 "), Some(("video/*", 50)));
+        assert_eq!(parse_size_limit("video/*"), None);
+        assert_eq!(parse_size_limit("video/*=0"), None);
+        assert_eq!(parse_size_limit("vid*o/mp4=1"), None);
+        assert!(is_valid_pattern("*"));
+        assert!(!is_valid_pattern("/*"));
+    }
+
+    #[test]
+    fn sniff_test() {
+        assert_eq!(sniff(b"\x89PNG\r\n\x1a\n\0\0"), Some("image/png"));
+        assert_eq!(sniff(b"RIFF\0\0\0\0WEBPVP8 "), Some("image/webp"));
+        assert_eq!(sniff(b"MZ\x90\0"), Some("application/x-msdownload"));
+        assert_eq!(sniff(b"\xef\xbb\xbf \n<!DOCTYPE HTML>"), Some("text/html"));
+        assert_eq!(sniff(b"<script>alert(1)</script>"), Some("text/html"));
+        assert_eq!(
+            sniff(b"<svg xmlns=\"http://www.w3.org/2000/svg\">"),
+            Some("image/svg+xml")
+        );
+        assert_eq!(
+            sniff(b"<?xml version=\"1.0\"?>\n<svg>"),
+            Some("image/svg+xml")
+        );
+        assert_eq!(
+            sniff(b"<?xml version=\"1.0\"?>\n<feed>"),
+            Some("application/xml")
+        );
+        assert_eq!(sniff(b"<header> is not html"), None);
+        assert_eq!(sniff(b"hello world"), None);
+        assert_eq!(sniff(b""), None);
+
+        assert!(is_active("text/html"));
+        assert!(is_active("Image/SVG+XML; charset=utf-8"));
+        assert!(is_active("application/atom+xml"));
+        assert!(!is_active("text/plain"));
+        assert!(!is_active("image/png"));
+    }
+}
@@ -0,0 +1,168 @@
+use std::{collections::HashMap, fmt, fs};
+
+use actix_web::{error::ErrorInternalServerError, Result};
+use anyhow::{anyhow, bail};
+use chacha20poly1305::{
+    aead::{Aead, AeadCore, KeyInit, OsRng, Payload},
+    XChaCha20Poly1305, XNonce,
+};
+
+use crate::config::StorageConfig;
+
+/// The key ID written for content that isn't encrypted.
+pub const NO_KEY: u16 = 0;
+
+const KEY_LEN: usize = 32;
+const NONCE_LEN: usize = 24;
+const TAG_LEN: usize = 16;
+
+/// All keys known to the server, and the one new content should be encrypted with.
+pub struct Keyring {
+    keys: HashMap<u16, XChaCha20Poly1305>,
+    active: u16,
+}
+
+impl Keyring {
+    /// A keyring without any keys. Content will be stored unencrypted.
+    pub fn empty() -> Self {
+        Self {
+            keys: HashMap::new(),
+            active: NO_KEY,
+        }
+    }
+
+    pub fn from_config(config: &StorageConfig) -> anyhow::Result<Self> {
+        let mut keyring = Self::empty();
+
+        if let Some(key_file) = config
+            .encryption_key_file
+            .as_ref()
+            .filter(|f| !f.is_empty())
+        {
+            let keys = fs::read_to_string(key_file)
+                .map_err(|err| anyhow!("Failed to read key file '{}': {}", key_file, err))?;
+            for (i, line) in keys.lines().enumerate() {
+                let line = line.trim();
+                if line.is_empty() || line.starts_with('#') {
+                    continue;
+                }
+                let Some((id, key)) = line.split_once(':') else {
+                    bail!(
+                        "Invalid key on line {} of '{}', expected '<id>:<hex key>'",
+                        i + 1,
+                        key_file
+                    );
+                };
+                let id = id
+                    .trim()
+                    .parse()
+                    .map_err(|_| anyhow!("Invalid key ID on line {} of '{}'", i + 1, key_file))?;
+                keyring.add_key(id, key.trim())?;
+            }
+        }
+
+        if let Some(key) = config.encryption_key.as_ref().filter(|k| !k.is_empty()) {
+            keyring.add_key(config.encryption_key_id, key)?;
+        }
+
+        if config.encryption {
+            if !keyring.keys.contains_key(&config.encryption_key_id) {
+                bail!(
+                    "Encryption is enabled, but no key with ID {} was configured",
+                    config.encryption_key_id
+                );
+            }
+            keyring.active = config.encryption_key_id;
+        }
+
+        Ok(keyring)
+    }
+
+    fn add_key(&mut self, id: u16, hex_key: &str) -> anyhow::Result<()> {
+        if id == NO_KEY {
+            bail!("Key ID {} is reserved for unencrypted content", NO_KEY);
+        }
+        let key = hex::decode(hex_key).map_err(|err| anyhow!("Invalid key {}: {}", id, err))?;
+        if key.len() != KEY_LEN {
+            bail!(
+                "Key {} must be {} bytes long, got {}",
+                id,
+                KEY_LEN,
+                key.len()
+            );
+        }
+        if self
+            .keys
+            .insert(id, XChaCha20Poly1305::new(key.as_slice().into()))
+            .is_some()
+        {
+            bail!("Key ID {} is configured more than once", id);
+        }
+        Ok(())
+    }
+
+    /// The ID of the key new content is encrypted with, or [`NO_KEY`] if encryption is disabled.
+    pub fn active_key_id(&self) -> u16 {
+        self.active
+    }
+
+    /// Whether content encrypted with `key_id` can be decrypted.
+    pub fn has_key(&self, key_id: u16) -> bool {
+        key_id == NO_KEY || self.keys.contains_key(&key_id)
+    }
+
+    /// Encrypts `data` with the active key, returning the nonce followed by the ciphertext.
+    pub fn encrypt(&self, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.cipher(self.active)?;
+        let nonce = XChaCha20Poly1305::generate_nonce(&mut OsRng);
+        let ciphertext = cipher
+            .encrypt(&nonce, Payload { msg: data, aad })
+            .map_err(|_| ErrorInternalServerError("Failed to encrypt content"))?;
+
+        let mut out = Vec::with_capacity(NONCE_LEN + ciphertext.len());
+        out.extend_from_slice(&nonce);
+        out.extend_from_slice(&ciphertext);
+        Ok(out)
+    }
+
+    /// Decrypts data previously returned by [`Keyring::encrypt`].
+    pub fn decrypt(&self, key_id: u16, aad: &[u8], data: &[u8]) -> Result<Vec<u8>> {
+        let cipher = self.cipher(key_id)?;
+        if data.len() < NONCE_LEN + TAG_LEN {
+            return Err(ErrorInternalServerError("Encrypted content is truncated"));
+        }
+        let (nonce, ciphertext) = data.split_at(NONCE_LEN);
+        cipher
+            .decrypt(
+                XNonce::from_slice(nonce),
+                Payload {
+                    msg: ciphertext,
+                    aad,
+                },
+            )
+            .map_err(|_| ErrorInternalServerError("Failed to decrypt content"))
+    }
+
+    fn cipher(&self, key_id: u16) -> Result<&XChaCha20Poly1305> {
+        self.keys
+            .get(&key_id)
+            .ok_or_else(|| ErrorInternalServerError(format!("Unknown encryption key {}", key_id)))
+    }
+}
+
+/// The length of the plaintext for `len` bytes of data returned by [`Keyring::encrypt`].
+pub fn plaintext_len(len: usize) -> usize {
+    len.saturating_sub(NONCE_LEN + TAG_LEN)
+}
+
+impl fmt::Debug for Keyring {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // Never print the keys themselves
+        let mut ids: Vec<&u16> = self.keys.keys().collect();
+        ids.sort();
+        f.debug_struct("Keyring")
+            .field("keys", &ids)
+            .field("active", &self.active)
+            .finish()
+    }
+}
@@ -12,20 +12,43 @@ impl<'a> DataReader<'a> {
         DataReader { buf: reader }
     }
 
-    pub fn read_int(&mut self) -> i32 {
-        self.buf.get_i32()
+    pub fn read_int(&mut self) -> Result<i32> {
+        self.buf.try_get_i32().map_err(ErrorInternalServerError)
+    }
+
+    pub fn read_uint(&mut self) -> Result<u32> {
+        self.buf.try_get_u32().map_err(ErrorInternalServerError)
     }
 
     pub fn read_int_as_usize(&mut self) -> Result<usize> {
-        self.read_int().try_into().map_err(ErrorInternalServerError)
+        self.read_int()?
+            .try_into()
+            .map_err(ErrorInternalServerError)
+    }
+
+    pub fn read_ushort(&mut self) -> Result<u16> {
+        self.buf.try_get_u16().map_err(ErrorInternalServerError)
     }
 
-    pub fn read_long(&mut self) -> i64 {
-        self.buf.get_i64()
+    pub fn read_long(&mut self) -> Result<i64> {
+        self.buf.try_get_i64().map_err(ErrorInternalServerError)
     }
 
-    pub fn read_bool(&mut self) -> bool {
-        self.buf.get_u8() != 0
+    pub fn read_bool(&mut self) -> Result<bool> {
+        Ok(self.buf.try_get_u8().map_err(ErrorInternalServerError)? != 0)
+    }
+
+    /// Fails if fewer than `len` bytes are left, so lengths read from the data can be checked
+    /// before allocating anything for them.
+    pub fn check_remaining(&self, len: usize) -> Result<()> {
+        if self.buf.len() < len {
+            return Err(ErrorInternalServerError(format!(
+                "Expected {} more bytes, but only {} are left",
+                len,
+                self.buf.len()
+            )));
+        }
+        Ok(())
     }
 
     pub fn read_fully(&mut self, buffer: &mut [u8]) -> Result<()> {
@@ -34,7 +57,7 @@ impl<'a> DataReader<'a> {
     }
 
     pub fn read_utf(&mut self) -> Result<String> {
-        let len = self.buf.get_u16().into();
+        let len = self.read_ushort()?.into();
         self.read_utf_of_len(len)
     }
 
@@ -44,6 +67,7 @@ impl<'a> DataReader<'a> {
     }
 
     pub fn read_utf_of_len(&mut self, len: usize) -> Result<String> {
+        self.check_remaining(len)?;
         let mut str_bytes = vec![0u8; len];
         self.buf.read_exact(&mut str_bytes)?;
         String::from_utf8(str_bytes).map_err(ErrorInternalServerError)
@@ -65,12 +89,20 @@ impl DataWriter {
         self.buf.put_i32(value);
     }
 
+    pub fn write_uint(&mut self, value: u32) {
+        self.buf.put_u32(value);
+    }
+
     pub fn write_int_from_usize(&mut self, value: usize) -> Result<()> {
         self.buf
             .put_i32(value.try_into().map_err(ErrorInternalServerError)?);
         Ok(())
     }
 
+    pub fn write_ushort(&mut self, value: u16) {
+        self.buf.put_u16(value);
+    }
+
     pub fn write_long(&mut self, value: i64) {
         self.buf.put_i64(value);
     }
@@ -97,6 +129,11 @@ impl DataWriter {
         Ok(())
     }
 
+    /// The data written so far.
+    pub fn as_slice(&self) -> &[u8] {
+        &self.buf
+    }
+
     pub fn get_data(self) -> Bytes {
         self.buf.freeze()
     }
@@ -1,10 +1,19 @@
+use std::sync::Arc;
+
 use actix_web::web;
-use anyhow::Result;
-use rusqlite::{types::Null, OptionalExtension};
+use anyhow::{bail, Result};
 use serde::{Deserialize, Serialize};
 
-pub type Pool = r2d2::Pool<r2d2_sqlite::SqliteConnectionManager>;
-pub type Connection = r2d2::PooledConnection<r2d2_sqlite::SqliteConnectionManager>;
+use self::query::ContentQuery;
+use crate::{config::DatabaseConfig, telemetry};
+
+pub mod migrations;
+#[cfg(feature = "postgres")]
+pub mod postgres;
+pub mod query;
+pub mod sqlite;
+
+pub type Metadata = Arc<dyn MetadataStore + Sync + Send>;
 
 #[derive(Clone, Serialize, Deserialize)]
 pub struct Content {
@@ -18,80 +27,202 @@ pub struct Content {
     pub backend_id: String,
     pub content_length: usize,
     pub content: Option<Vec<u8>>,
+    /// How many times the content can be viewed before it's deleted.
+    pub max_views: Option<u32>,
+    /// Only tracked in the database, and only for content with `max_views`.
+    pub views: u32,
+    /// An Argon2 hash of the password needed to view the content.
+    pub password_hash: Option<String>,
+}
+
+#[cfg(test)]
+impl Content {
+    /// Plain text content that's only in the database, for tests to build on.
+    pub fn for_tests(key: &str) -> Content {
+        Content {
+            key: key.to_string(),
+            content_type: "text/plain".to_string(),
+            expiry: None,
+            last_modified: 1721160516802,
+            modifiable: false,
+            auth_key: None,
+            content_encoding: "gzip".to_string(),
+            backend_id: "local".to_string(),
+            content_length: 157,
+            content: None,
+            max_views: None,
+            views: 0,
+            password_hash: None,
+        }
+    }
+}
+
+/// How much content has been used, tracked separately from [`Content`].
+#[derive(Clone, Copy, Default, Debug, PartialEq, Serialize)]
+pub struct ContentStats {
+    pub views: u64,
+    pub bytes_served: u64,
+    /// In milliseconds since the epoch, like `last_modified`
+    pub last_accessed: Option<i64>,
+}
+
+impl ContentStats {
+    /// Combines stats from separate periods.
+    pub fn add(&mut self, other: &ContentStats) {
+        self.views += other.views;
+        self.bytes_served += other.bytes_served;
+        self.last_accessed = self.last_accessed.max(other.last_accessed);
+    }
+}
+
+/// The stats of all content combined.
+#[derive(Clone, Copy, Default, Debug, PartialEq, Serialize)]
+pub struct TotalStats {
+    pub pastes: u64,
+    pub content_length: u64,
+    pub views: u64,
+    pub bytes_served: u64,
+}
+
+/// Where information about content is kept, so it can be served without reading it from storage.
+///
+/// All methods block, so they should be called through the async functions in this module.
+pub trait MetadataStore {
+    fn backend_id(&self) -> &'static str;
+    /// Returns false without saving anything if the key is already used.
+    fn save_content_info(&self, content: &Content) -> Result<bool>;
+    fn get_content_info(&self, key: &str) -> Result<Option<Content>>;
+    /// Returns whether there was anything to delete.
+    fn delete_content_info(&self, key: &str) -> Result<bool>;
+    /// Counts a view of content with `max_views`, returning its info including this view.
+    /// Returns None if it doesn't exist or has no views left, so the limit can't be exceeded.
+    fn record_view(&self, key: &str) -> Result<Option<Content>>;
+    fn list_content_info(&self) -> Result<Vec<Content>>;
+    /// Returns a page of the content matching `query`, and how much matches in total.
+    fn search_content_info(&self, query: &ContentQuery) -> Result<(Vec<Content>, u64)>;
+    /// Deletes everything that expired at or before `now`, or has no views left,
+    /// returning the deleted keys.
+    fn delete_expired(&self, now: i64) -> Result<Vec<String>>;
+    /// Deletes everything that hasn't been accessed since `before`, returning the deleted keys.
+    /// Content that was never accessed counts as accessed when it was created.
+    fn delete_inactive(&self, before: i64) -> Result<Vec<String>>;
+    /// Adds to the stats of each key, skipping keys that don't exist anymore.
+    fn add_stats(&self, stats: &[(String, ContentStats)]) -> Result<()>;
+    fn get_stats(&self, key: &str) -> Result<Option<ContentStats>>;
+    fn total_stats(&self) -> Result<TotalStats>;
+    /// Flushes anything the store buffers internally to permanent storage.
+    fn checkpoint(&self) -> Result<()> {
+        Ok(())
+    }
 }
 
-pub fn create_db(conn: Connection) -> Result<usize> {
-    Ok(conn.execute(
-        "CREATE TABLE `content` (
-            `key` VARCHAR NOT NULL ,
-            `content_type` VARCHAR ,
-            `expiry` INTEGER ,
-            `last_modified` BIGINT ,
-            `encoding` VARCHAR ,
-            `backend_id` VARCHAR ,
-            `content_length` INTEGER ,
-            PRIMARY KEY (`key`)
-        );",
-        (),
-    )?)
-}
-
-pub async fn save_content_info(pool: &Pool, content: &Content) -> Result<usize> {
-    let pool = pool.clone();
-
-    let conn = web::block(move || pool.get()).await??;
-
-    let content = content.clone();
-
-    web::block(move || {
-        // INSERT INTO content VALUES('2TIzc','text/plain',NULL,1721160516802,'gzip','local',157);
-        Ok(conn.execute(
-            "INSERT INTO content (
-                key,
-                content_type,
-                expiry,
-                last_modified, 
-                encoding,
-                backend_id,
-                content_length
-                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7);",
-            (
-                content.key,
-                content.content_type,
-                Null, // We don't support expiration
-                content.last_modified,
-                content.content_encoding,
-                content.backend_id,
-                content.content_length,
-            ),
-        )?)
+/// Opens the configured metadata store, applying any pending migrations.
+/// Also returns whether it was just created, in which case it should be populated from storage.
+pub async fn open(config: &DatabaseConfig) -> Result<(Metadata, bool)> {
+    let config = config.clone();
+    // Some stores run their own runtime internally, so they can't be set up on this thread.
+    web::block(move || -> Result<(Metadata, bool)> {
+        match config.backend.as_str() {
+            "sqlite" => {
+                let (store, new) = sqlite::SqliteStore::open(&config)?;
+                Ok((Arc::new(store), new))
+            }
+            #[cfg(feature = "postgres")]
+            "postgres" => {
+                let (store, new) = postgres::PostgresStore::open(&config)?;
+                Ok((Arc::new(store), new))
+            }
+            #[cfg(not(feature = "postgres"))]
+            "postgres" => bail!("bitbin was built without PostgreSQL support! Rebuild it with `--features postgres`."),
+            backend => bail!("Unknown database backend '{}'", backend),
+        }
     })
     .await?
 }
 
-pub async fn get_content_info(pool: &Pool, key: String) -> Result<Option<Content>> {
-    let pool = pool.clone();
-
-    let conn = web::block(move || pool.get()).await??;
-
-    web::block(move || {
-        let mut stmt = conn.prepare("SELECT * FROM content WHERE key=:key;")?;
-        Ok(stmt
-            .query_row(&[(":key", &key)], |row| {
-                Ok(Content {
-                    key: row.get(0)?,
-                    content_type: row.get(1)?,
-                    expiry: row.get(2)?,
-                    last_modified: row.get(3)?,
-                    modifiable: false,
-                    auth_key: None,
-                    content_encoding: row.get(4)?,
-                    backend_id: row.get(5)?,
-                    content_length: row.get(6)?,
-                    content: None,
-                })
-            })
-            .optional()?)
-    })
+pub async fn save_content_info(store: &Metadata, content: &Content) -> Result<bool> {
+    let store = store.clone();
+    let saved = content.clone();
+    telemetry::traced_key(
+        "db.save_content_info",
+        &content.key,
+        web::block(move || store.save_content_info(&saved)),
+    )
+    .await?
+}
+
+pub async fn get_content_info(store: &Metadata, key: String) -> Result<Option<Content>> {
+    let store = store.clone();
+    let blocking_key = key.clone();
+    telemetry::traced_key(
+        "db.get_content_info",
+        &key,
+        web::block(move || store.get_content_info(&blocking_key)),
+    )
+    .await?
+}
+
+pub async fn delete_content_info(store: &Metadata, key: String) -> Result<bool> {
+    let store = store.clone();
+    let blocking_key = key.clone();
+    telemetry::traced_key(
+        "db.delete_content_info",
+        &key,
+        web::block(move || store.delete_content_info(&blocking_key)),
+    )
     .await?
 }
+
+pub async fn record_view(store: &Metadata, key: String) -> Result<Option<Content>> {
+    let store = store.clone();
+    let blocking_key = key.clone();
+    telemetry::traced_key(
+        "db.record_view",
+        &key,
+        web::block(move || store.record_view(&blocking_key)),
+    )
+    .await?
+}
+
+pub async fn list_content_info(store: &Metadata) -> Result<Vec<Content>> {
+    let store = store.clone();
+    web::block(move || store.list_content_info()).await?
+}
+
+pub async fn search_content_info(
+    store: &Metadata,
+    query: ContentQuery,
+) -> Result<(Vec<Content>, u64)> {
+    let store = store.clone();
+    web::block(move || store.search_content_info(&query)).await?
+}
+
+pub async fn delete_expired(store: &Metadata, now: i64) -> Result<Vec<String>> {
+    let store = store.clone();
+    web::block(move || store.delete_expired(now)).await?
+}
+
+pub async fn delete_inactive(store: &Metadata, before: i64) -> Result<Vec<String>> {
+    let store = store.clone();
+    web::block(move || store.delete_inactive(before)).await?
+}
+
+pub async fn add_stats(store: &Metadata, stats: Vec<(String, ContentStats)>) -> Result<()> {
+    let store = store.clone();
+    web::block(move || store.add_stats(&stats)).await?
+}
+
+pub async fn get_stats(store: &Metadata, key: String) -> Result<Option<ContentStats>> {
+    let store = store.clone();
+    web::block(move || store.get_stats(&key)).await?
+}
+
+pub async fn total_stats(store: &Metadata) -> Result<TotalStats> {
+    let store = store.clone();
+    web::block(move || store.total_stats()).await?
+}
+
+pub async fn checkpoint(store: &Metadata) -> Result<()> {
+    let store = store.clone();
+    web::block(move || store.checkpoint()).await?
+}
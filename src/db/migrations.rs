@@ -0,0 +1,95 @@
+use anyhow::{bail, Result};
+use log::info;
+use rusqlite::{Connection, TransactionBehavior};
+
+/// Each entry upgrades the schema by one version, with the first one creating it.
+/// The current version is stored in `PRAGMA user_version`.
+///
+/// Never modify a migration that's already been released, always add a new one!
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema. `IF NOT EXISTS` since databases created before migrations were
+    // introduced already have this table, but are still on version 0.
+    "CREATE TABLE IF NOT EXISTS `content` (
+        `key` VARCHAR NOT NULL ,
+        `content_type` VARCHAR ,
+        `expiry` INTEGER ,
+        `last_modified` BIGINT ,
+        `encoding` VARCHAR ,
+        `backend_id` VARCHAR ,
+        `content_length` INTEGER ,
+        PRIMARY KEY (`key`)
+    );",
+    // 2: View limits
+    "ALTER TABLE `content` ADD COLUMN `max_views` INTEGER;
+    ALTER TABLE `content` ADD COLUMN `views` INTEGER NOT NULL DEFAULT 0;",
+    // 3: Passwords
+    "ALTER TABLE `content` ADD COLUMN `password_hash` VARCHAR;",
+    // 4: Access stats. Existing content counts as accessed now, so enabling inactivity expiry
+    // right after upgrading doesn't delete everything that's older.
+    "ALTER TABLE `content` ADD COLUMN `view_count` INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE `content` ADD COLUMN `bytes_served` INTEGER NOT NULL DEFAULT 0;
+    ALTER TABLE `content` ADD COLUMN `last_accessed` BIGINT;
+    UPDATE `content` SET `last_accessed` = CAST(strftime('%s', 'now') AS INTEGER) * 1000;",
+];
+
+/// Brings the database schema up to date, refusing to touch databases with a newer schema.
+pub fn migrate(conn: &mut Connection) -> Result<()> {
+    // Exclusive so multiple instances starting at once can't both apply the same migration
+    let tx = conn.transaction_with_behavior(TransactionBehavior::Exclusive)?;
+
+    let version: usize = tx.pragma_query_value(None, "user_version", |row| row.get(0))?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "The database schema is at version {}, but this version of bitbin only supports up to version {}! Refusing to start.",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let version = i + 1;
+        info!("Migrating database schema to version {}...", version);
+        tx.execute_batch(migration)?;
+        tx.pragma_update(None, "user_version", version)?;
+    }
+
+    tx.commit()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn user_version(conn: &Connection) -> usize {
+        conn.pragma_query_value(None, "user_version", |row| row.get(0))
+            .unwrap()
+    }
+
+    #[test]
+    fn migrate_test() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+
+        // Running again is a no-op
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrate_unversioned_test() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.execute_batch(MIGRATIONS[0]).unwrap();
+        migrate(&mut conn).unwrap();
+        assert_eq!(user_version(&conn), MIGRATIONS.len());
+    }
+
+    #[test]
+    fn migrate_newer_test() {
+        let mut conn = Connection::open_in_memory().unwrap();
+        conn.pragma_update(None, "user_version", MIGRATIONS.len() + 1)
+            .unwrap();
+        assert!(migrate(&mut conn).is_err());
+    }
+}
@@ -0,0 +1,378 @@
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use postgres::{types::ToSql, Client, NoTls, Row};
+use r2d2_postgres::PostgresConnectionManager;
+
+use super::{
+    query::{ContentQuery, Param},
+    Content, ContentStats, MetadataStore, TotalStats,
+};
+use crate::config::DatabaseConfig;
+
+pub type Pool = r2d2::Pool<PostgresConnectionManager<NoTls>>;
+
+/// Same as the SQLite migrations, but tracked in the `bitbin_schema` table since Postgres
+/// doesn't have `user_version`.
+const MIGRATIONS: &[&str] = &[
+    // 1: Initial schema
+    "CREATE TABLE IF NOT EXISTS content (
+        key VARCHAR NOT NULL PRIMARY KEY,
+        content_type VARCHAR,
+        expiry BIGINT,
+        last_modified BIGINT,
+        encoding VARCHAR,
+        backend_id VARCHAR,
+        content_length BIGINT
+    );",
+    // 2: View limits
+    "ALTER TABLE content ADD COLUMN max_views BIGINT, ADD COLUMN views BIGINT NOT NULL DEFAULT 0;",
+    // 3: Passwords
+    "ALTER TABLE content ADD COLUMN password_hash VARCHAR;",
+    // 4: Access stats
+    "ALTER TABLE content ADD COLUMN view_count BIGINT NOT NULL DEFAULT 0,
+        ADD COLUMN bytes_served BIGINT NOT NULL DEFAULT 0,
+        ADD COLUMN last_accessed BIGINT;
+    UPDATE content SET last_accessed = (EXTRACT(EPOCH FROM now()) * 1000)::BIGINT;",
+];
+
+/// Held while migrating, so multiple instances starting at once don't step on each other.
+const MIGRATION_LOCK_ID: i64 = 0x6269_7462_696e; // "bitbin"
+
+pub struct PostgresStore {
+    pool: Pool,
+}
+
+impl PostgresStore {
+    pub fn open(config: &DatabaseConfig) -> Result<(Self, bool)> {
+        let url = config
+            .postgres_url
+            .as_ref()
+            .filter(|url| !url.is_empty())
+            .ok_or_else(|| anyhow!("The postgres backend requires database.postgres_url"))?;
+
+        let manager = PostgresConnectionManager::new(url.parse()?, NoTls);
+        let pool = Pool::builder().max_size(config.pool_size).build(manager)?;
+
+        let new_db = migrate(&mut *pool.get()?)?;
+
+        Ok((Self { pool }, new_db))
+    }
+}
+
+/// Brings the schema up to date, returning whether it was just created.
+fn migrate(client: &mut Client) -> Result<bool> {
+    let mut tx = client.transaction()?;
+    tx.execute("SELECT pg_advisory_xact_lock($1);", &[&MIGRATION_LOCK_ID])?;
+    tx.batch_execute("CREATE TABLE IF NOT EXISTS bitbin_schema (version INTEGER NOT NULL);")?;
+
+    let version: i32 = tx
+        .query_opt("SELECT version FROM bitbin_schema;", &[])?
+        .map(|row| row.get(0))
+        .unwrap_or(0);
+    let version = usize::try_from(version)?;
+    if version > MIGRATIONS.len() {
+        bail!(
+            "The database schema is at version {}, but this version of bitbin only supports up to version {}! Refusing to start.",
+            version,
+            MIGRATIONS.len()
+        );
+    }
+
+    for (i, migration) in MIGRATIONS.iter().enumerate().skip(version) {
+        let version = i + 1;
+        info!("Migrating database schema to version {}...", version);
+        tx.batch_execute(migration)?;
+        tx.execute("DELETE FROM bitbin_schema;", &[])?;
+        tx.execute(
+            "INSERT INTO bitbin_schema (version) VALUES ($1);",
+            &[&i32::try_from(version)?],
+        )?;
+    }
+
+    tx.commit()?;
+    Ok(version == 0)
+}
+
+impl MetadataStore for PostgresStore {
+    fn backend_id(&self) -> &'static str {
+        "postgres"
+    }
+
+    fn save_content_info(&self, content: &Content) -> Result<bool> {
+        let inserted = self.pool.get()?.execute(
+            "INSERT INTO content (
+                key,
+                content_type,
+                expiry,
+                last_modified,
+                encoding,
+                backend_id,
+                content_length,
+                max_views,
+                password_hash
+                ) VALUES ($1, $2, $3, $4, $5, $6, $7, $8, $9)
+                ON CONFLICT (key) DO NOTHING;",
+            &[
+                &content.key,
+                &content.content_type,
+                &content.expiry,
+                &content.last_modified,
+                &content.content_encoding,
+                &content.backend_id,
+                &i64::try_from(content.content_length)?,
+                &content.max_views.map(i64::from),
+                &content.password_hash,
+            ],
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn get_content_info(&self, key: &str) -> Result<Option<Content>> {
+        self.pool
+            .get()?
+            .query_opt("SELECT * FROM content WHERE key = $1;", &[&key])?
+            .map(|row| content_from_row(&row))
+            .transpose()
+    }
+
+    fn delete_content_info(&self, key: &str) -> Result<bool> {
+        let deleted = self
+            .pool
+            .get()?
+            .execute("DELETE FROM content WHERE key = $1;", &[&key])?;
+        Ok(deleted > 0)
+    }
+
+    fn record_view(&self, key: &str) -> Result<Option<Content>> {
+        // A single statement, so concurrent views can't both take the last one
+        self.pool
+            .get()?
+            .query_opt(
+                "UPDATE content SET views = views + 1
+                    WHERE key = $1 AND max_views IS NOT NULL AND views < max_views
+                    RETURNING *;",
+                &[&key],
+            )?
+            .map(|row| content_from_row(&row))
+            .transpose()
+    }
+
+    fn list_content_info(&self) -> Result<Vec<Content>> {
+        self.pool
+            .get()?
+            .query("SELECT * FROM content;", &[])?
+            .iter()
+            .map(content_from_row)
+            .collect()
+    }
+
+    fn search_content_info(&self, query: &ContentQuery) -> Result<(Vec<Content>, u64)> {
+        let sql = query.to_sql(|i| format!("${}", i));
+        let filter_params: Vec<_> = sql.filter_params.iter().map(sql_param).collect();
+        let page_params: Vec<_> = sql.page_params.iter().map(sql_param).collect();
+        let filter_params: Vec<&(dyn ToSql + Sync)> =
+            filter_params.iter().map(|param| param.as_ref()).collect();
+        let all_params: Vec<&(dyn ToSql + Sync)> = filter_params
+            .iter()
+            .copied()
+            .chain(page_params.iter().map(|param| param.as_ref()))
+            .collect();
+
+        let mut conn = self.pool.get()?;
+        let total: i64 = conn
+            .query_one(
+                &format!("SELECT COUNT(*) FROM content {};", sql.filter),
+                &filter_params,
+            )?
+            .try_get(0)?;
+        let content = conn
+            .query(
+                &format!("SELECT * FROM content {} {};", sql.filter, sql.page),
+                &all_params,
+            )?
+            .iter()
+            .map(content_from_row)
+            .collect::<Result<_>>()?;
+        Ok((content, total.try_into()?))
+    }
+
+    fn delete_expired(&self, now: i64) -> Result<Vec<String>> {
+        Ok(self
+            .pool
+            .get()?
+            .query(
+                "DELETE FROM content
+                    WHERE (expiry IS NOT NULL AND expiry <= $1)
+                        OR (max_views IS NOT NULL AND views >= max_views)
+                    RETURNING key;",
+                &[&now],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    fn delete_inactive(&self, before: i64) -> Result<Vec<String>> {
+        Ok(self
+            .pool
+            .get()?
+            .query(
+                "DELETE FROM content WHERE COALESCE(last_accessed, last_modified) < $1 RETURNING key;",
+                &[&before],
+            )?
+            .iter()
+            .map(|row| row.get(0))
+            .collect())
+    }
+
+    fn add_stats(&self, stats: &[(String, ContentStats)]) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        let mut tx = conn.transaction()?;
+        let stmt = tx.prepare(
+            "UPDATE content SET
+                view_count = view_count + $2,
+                bytes_served = bytes_served + $3,
+                last_accessed = GREATEST(last_accessed, $4)
+                WHERE key = $1;",
+        )?;
+        for (key, stats) in stats {
+            tx.execute(
+                &stmt,
+                &[
+                    key,
+                    &i64::try_from(stats.views)?,
+                    &i64::try_from(stats.bytes_served)?,
+                    &stats.last_accessed,
+                ],
+            )?;
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_stats(&self, key: &str) -> Result<Option<ContentStats>> {
+        self.pool
+            .get()?
+            .query_opt(
+                "SELECT view_count, bytes_served, last_accessed FROM content WHERE key = $1;",
+                &[&key],
+            )?
+            .map(|row| {
+                Ok(ContentStats {
+                    views: row.try_get::<_, i64>(0)?.try_into()?,
+                    bytes_served: row.try_get::<_, i64>(1)?.try_into()?,
+                    last_accessed: row.try_get(2)?,
+                })
+            })
+            .transpose()
+    }
+
+    fn total_stats(&self) -> Result<TotalStats> {
+        let row = self.pool.get()?.query_one(
+            "SELECT COUNT(*),
+                COALESCE(SUM(content_length), 0)::BIGINT,
+                COALESCE(SUM(view_count), 0)::BIGINT,
+                COALESCE(SUM(bytes_served), 0)::BIGINT
+                FROM content;",
+            &[],
+        )?;
+        let get = |i: usize| -> Result<u64> { Ok(row.try_get::<_, i64>(i)?.try_into()?) };
+        Ok(TotalStats {
+            pastes: get(0)?,
+            content_length: get(1)?,
+            views: get(2)?,
+            bytes_served: get(3)?,
+        })
+    }
+}
+
+fn sql_param(param: &Param) -> Box<dyn ToSql + Sync> {
+    match param {
+        Param::Int(value) => Box::new(*value),
+        Param::Text(value) => Box::new(value.clone()),
+    }
+}
+
+fn content_from_row(row: &Row) -> Result<Content> {
+    Ok(Content {
+        key: row.try_get(0)?,
+        content_type: row.try_get(1)?,
+        expiry: row.try_get(2)?,
+        last_modified: row.try_get(3)?,
+        modifiable: false,
+        auth_key: None,
+        content_encoding: row.try_get(4)?,
+        backend_id: row.try_get(5)?,
+        content_length: row.try_get::<_, i64>(6)?.try_into()?,
+        content: None,
+        max_views: row
+            .try_get::<_, Option<i64>>(7)?
+            .map(u32::try_from)
+            .transpose()?,
+        views: row.try_get::<_, i64>(8)?.try_into()?,
+        password_hash: row.try_get(9)?,
+    })
+}
+
+/// These run against the database in `BITBIN_TEST_POSTGRES_URL`, so they're ignored by default.
+/// `docker run --rm -e POSTGRES_PASSWORD=bitbin -p 5432:5432 postgres` will do, then run
+/// `cargo test --features postgres -- --ignored`.
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store() -> PostgresStore {
+        let url = std::env::var("BITBIN_TEST_POSTGRES_URL")
+            .expect("BITBIN_TEST_POSTGRES_URL must be set to run the Postgres tests");
+        let config = DatabaseConfig {
+            backend: "postgres".to_string(),
+            postgres_url: Some(url),
+            ..Default::default()
+        };
+        PostgresStore::open(&config).unwrap().0
+    }
+
+    #[test]
+    #[ignore = "needs a Postgres database in BITBIN_TEST_POSTGRES_URL"]
+    fn store_test() {
+        let store = store();
+        let key = format!("test{}", random_string::generate(8, "abcdef"));
+        let expiring_key = format!("{}e", key);
+
+        store.save_content_info(&Content::for_tests(&key)).unwrap();
+        store
+            .save_content_info(&Content {
+                expiry: Some(1000),
+                ..Content::for_tests(&expiring_key)
+            })
+            .unwrap();
+        assert!(!store.save_content_info(&Content::for_tests(&key)).unwrap());
+
+        let read = store.get_content_info(&key).unwrap().unwrap();
+        assert_eq!(read.content_length, 157);
+        assert_eq!(read.expiry, None);
+        assert!(store
+            .list_content_info()
+            .unwrap()
+            .iter()
+            .any(|c| c.key == key));
+
+        assert!(store.delete_expired(1000).unwrap().contains(&expiring_key));
+        assert!(store.get_content_info(&expiring_key).unwrap().is_none());
+
+        let limited_key = format!("{}v", key);
+        store
+            .save_content_info(&Content {
+                max_views: Some(1),
+                ..Content::for_tests(&limited_key)
+            })
+            .unwrap();
+        assert_eq!(store.record_view(&limited_key).unwrap().unwrap().views, 1);
+        assert!(store.record_view(&limited_key).unwrap().is_none());
+        assert!(store.record_view(&key).unwrap().is_none());
+        assert!(store.delete_expired(0).unwrap().contains(&limited_key));
+
+        assert!(store.delete_content_info(&key).unwrap());
+        assert!(!store.delete_content_info(&key).unwrap());
+    }
+}
@@ -0,0 +1,216 @@
+use serde::Deserialize;
+
+/// Columns content can be sorted by.
+const SORT_COLUMNS: &[&str] = &[
+    "key",
+    "content_type",
+    "expiry",
+    "last_modified",
+    "encoding",
+    "backend_id",
+    "content_length",
+    "max_views",
+    "views",
+    "view_count",
+    "bytes_served",
+    "last_accessed",
+];
+const DEFAULT_LIMIT: u64 = 100;
+const MAX_LIMIT: u64 = 1000;
+
+/// Filters, sorting and pagination for listing content. Times are in milliseconds since the epoch.
+#[derive(Clone, Default, Debug, Deserialize)]
+#[serde(default)]
+pub struct ContentQuery {
+    /// Either an exact content type, or a prefix ending in `*` like `text/*`.
+    pub content_type: Option<String>,
+    pub backend_id: Option<String>,
+    /// In bytes, as stored
+    pub min_size: Option<u64>,
+    pub max_size: Option<u64>,
+    pub created_after: Option<i64>,
+    pub created_before: Option<i64>,
+    /// Whether the content expires at all.
+    pub expires: Option<bool>,
+    pub expires_after: Option<i64>,
+    pub expires_before: Option<i64>,
+    /// One of [`SORT_COLUMNS`], `last_modified` if not given.
+    pub sort: Option<String>,
+    pub desc: bool,
+    pub limit: Option<u64>,
+    pub offset: u64,
+}
+
+/// A value to bind to a query.
+#[derive(Clone, Debug, PartialEq)]
+pub enum Param {
+    Int(i64),
+    Text(String),
+}
+
+/// SQL for a [`ContentQuery`], with placeholders in the store's syntax.
+pub struct QuerySql {
+    /// A `WHERE` clause, or an empty string
+    pub filter: String,
+    /// The `ORDER BY`, `LIMIT` and `OFFSET` clauses
+    pub page: String,
+    pub filter_params: Vec<Param>,
+    pub page_params: Vec<Param>,
+}
+
+impl ContentQuery {
+    /// Checks the query, returning why it can't be used.
+    pub fn validate(&self) -> Result<(), &'static str> {
+        if self
+            .sort
+            .as_ref()
+            .is_some_and(|sort| !SORT_COLUMNS.contains(&sort.as_str()))
+        {
+            return Err("Unknown sort column");
+        }
+        if self
+            .limit
+            .is_some_and(|limit| limit == 0 || limit > MAX_LIMIT)
+        {
+            return Err("limit must be between 1 and 1000");
+        }
+        if self.min_size.is_some_and(|size| size > i64::MAX as u64)
+            || self.max_size.is_some_and(|size| size > i64::MAX as u64)
+            || self.offset > i64::MAX as u64
+        {
+            return Err("Number out of range");
+        }
+        Ok(())
+    }
+
+    pub fn limit(&self) -> u64 {
+        self.limit.unwrap_or(DEFAULT_LIMIT)
+    }
+
+    /// Builds the SQL, numbering placeholders with `placeholder`. The query must be valid.
+    pub fn to_sql(&self, placeholder: impl Fn(usize) -> String) -> QuerySql {
+        let mut conditions = Vec::new();
+        let mut params = Vec::new();
+        let mut condition = |sql: &str, param: Param| {
+            params.push(param);
+            conditions.push(sql.replace('?', &placeholder(params.len())));
+        };
+
+        if let Some(content_type) = &self.content_type {
+            match content_type.strip_suffix('*') {
+                Some(prefix) => condition(
+                    "content_type LIKE ? ESCAPE '\\'",
+                    Param::Text(format!("{}%", escape_like(prefix))),
+                ),
+                None => condition("content_type = ?", Param::Text(content_type.clone())),
+            }
+        }
+        if let Some(backend_id) = &self.backend_id {
+            condition("backend_id = ?", Param::Text(backend_id.clone()));
+        }
+        if let Some(min_size) = self.min_size {
+            condition("content_length >= ?", Param::Int(min_size as i64));
+        }
+        if let Some(max_size) = self.max_size {
+            condition("content_length <= ?", Param::Int(max_size as i64));
+        }
+        if let Some(created_after) = self.created_after {
+            condition("last_modified >= ?", Param::Int(created_after));
+        }
+        if let Some(created_before) = self.created_before {
+            condition("last_modified < ?", Param::Int(created_before));
+        }
+        if let Some(expires_after) = self.expires_after {
+            condition("expiry >= ?", Param::Int(expires_after));
+        }
+        if let Some(expires_before) = self.expires_before {
+            condition("expiry < ?", Param::Int(expires_before));
+        }
+        match self.expires {
+            Some(true) => conditions.push("expiry IS NOT NULL".to_string()),
+            Some(false) => conditions.push("expiry IS NULL".to_string()),
+            None => {}
+        }
+
+        let filter = if conditions.is_empty() {
+            String::new()
+        } else {
+            format!("WHERE {}", conditions.join(" AND "))
+        };
+        let offset = params.len();
+        // Only ever one of our own column names, even if the query wasn't validated
+        let sort = SORT_COLUMNS
+            .iter()
+            .find(|column| Some(**column) == self.sort.as_deref())
+            .unwrap_or(&"last_modified");
+        // Sorted by key as well, so pages don't overlap when the sort column has duplicates
+        let page = format!(
+            "ORDER BY {} {}, key LIMIT {} OFFSET {}",
+            sort,
+            if self.desc { "DESC" } else { "ASC" },
+            placeholder(offset + 1),
+            placeholder(offset + 2)
+        );
+        QuerySql {
+            filter,
+            page,
+            filter_params: params,
+            page_params: vec![
+                Param::Int(self.limit() as i64),
+                Param::Int(self.offset as i64),
+            ],
+        }
+    }
+}
+
+fn escape_like(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('%', "\\%")
+        .replace('_', "\\_")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn to_sql_test() {
+        let query = ContentQuery {
+            content_type: Some("text/*".to_string()),
+            min_size: Some(10),
+            expires: Some(false),
+            sort: Some("content_length".to_string()),
+            desc: true,
+            ..Default::default()
+        };
+        assert!(query.validate().is_ok());
+
+        let sql = query.to_sql(|i| format!("${}", i));
+        assert_eq!(
+            sql.filter,
+            "WHERE content_type LIKE $1 ESCAPE '\\' AND content_length >= $2 AND expiry IS NULL"
+        );
+        assert_eq!(sql.filter_params[0], Param::Text("text/%".to_string()));
+        assert_eq!(
+            sql.page,
+            "ORDER BY content_length DESC, key LIMIT $3 OFFSET $4"
+        );
+        assert_eq!(sql.page_params, vec![Param::Int(100), Param::Int(0)]);
+
+        let sql = ContentQuery::default().to_sql(|i| format!("?{}", i));
+        assert_eq!(sql.filter, "");
+        assert_eq!(
+            sql.page,
+            "ORDER BY last_modified ASC, key LIMIT ?1 OFFSET ?2"
+        );
+
+        let invalid = ContentQuery {
+            sort: Some("key; DROP TABLE content".to_string()),
+            ..Default::default()
+        };
+        assert!(invalid.validate().is_err());
+        assert!(!invalid.to_sql(|i| format!("?{}", i)).page.contains("DROP"));
+        assert_eq!(escape_like("a_b%"), "a\\_b\\%");
+    }
+}
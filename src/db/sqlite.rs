@@ -0,0 +1,447 @@
+use std::{fs, path::PathBuf, time::Duration};
+
+use anyhow::{bail, Result};
+use r2d2_sqlite::SqliteConnectionManager;
+use rusqlite::{params_from_iter, types::Value, OptionalExtension, Row};
+
+use super::{
+    migrations,
+    query::{ContentQuery, Param},
+    Content, ContentStats, MetadataStore, TotalStats,
+};
+use crate::config::DatabaseConfig;
+
+pub type Pool = r2d2::Pool<SqliteConnectionManager>;
+
+pub const SYNCHRONOUS_LEVELS: &[&str] = &["off", "normal", "full", "extra"];
+
+pub struct SqliteStore {
+    pool: Pool,
+    wal: bool,
+}
+
+impl SqliteStore {
+    pub fn open(config: &DatabaseConfig) -> Result<(Self, bool)> {
+        let db_path = PathBuf::from(&config.path);
+        if let Some(db_dir) = db_path.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+            if !db_dir.exists() {
+                fs::create_dir_all(db_dir)?;
+            }
+        }
+
+        let new_db = !db_path.exists();
+
+        let synchronous = config.synchronous.to_lowercase();
+        if !SYNCHRONOUS_LEVELS.contains(&synchronous.as_str()) {
+            bail!(
+                "Invalid synchronous level '{}', expected one of {:?}",
+                config.synchronous,
+                SYNCHRONOUS_LEVELS
+            );
+        }
+
+        // Applied to every connection the pool opens
+        let busy_timeout = Duration::from_millis(config.busy_timeout);
+        let journal_mode = if config.wal { "WAL" } else { "DELETE" };
+        let manager = SqliteConnectionManager::file(db_path).with_init(move |conn| {
+            conn.busy_timeout(busy_timeout)?;
+            conn.pragma_update(None, "journal_mode", journal_mode)?;
+            conn.pragma_update(None, "synchronous", &synchronous)?;
+            Ok(())
+        });
+        let pool = Pool::builder().max_size(config.pool_size).build(manager)?;
+
+        migrations::migrate(&mut *pool.get()?)?;
+
+        Ok((
+            Self {
+                pool,
+                wal: config.wal,
+            },
+            new_db,
+        ))
+    }
+}
+
+impl MetadataStore for SqliteStore {
+    fn backend_id(&self) -> &'static str {
+        "sqlite"
+    }
+
+    fn save_content_info(&self, content: &Content) -> Result<bool> {
+        // INSERT INTO content VALUES('2TIzc','text/plain',NULL,1721160516802,'gzip','local',157);
+        let inserted = self.pool.get()?.execute(
+            "INSERT INTO content (
+                key,
+                content_type,
+                expiry,
+                last_modified,
+                encoding,
+                backend_id,
+                content_length,
+                max_views,
+                password_hash
+                ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9)
+                ON CONFLICT (key) DO NOTHING;",
+            (
+                &content.key,
+                &content.content_type,
+                content.expiry,
+                content.last_modified,
+                &content.content_encoding,
+                &content.backend_id,
+                content.content_length,
+                content.max_views,
+                &content.password_hash,
+            ),
+        )?;
+        Ok(inserted == 1)
+    }
+
+    fn get_content_info(&self, key: &str) -> Result<Option<Content>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT * FROM content WHERE key=:key;")?;
+        Ok(stmt
+            .query_row(&[(":key", key)], content_from_row)
+            .optional()?)
+    }
+
+    fn delete_content_info(&self, key: &str) -> Result<bool> {
+        let deleted = self
+            .pool
+            .get()?
+            .execute("DELETE FROM content WHERE key=?1;", (key,))?;
+        Ok(deleted > 0)
+    }
+
+    fn record_view(&self, key: &str) -> Result<Option<Content>> {
+        let conn = self.pool.get()?;
+        // A single statement, so concurrent views can't both take the last one
+        let mut stmt = conn.prepare(
+            "UPDATE content SET views = views + 1
+                WHERE key = ?1 AND max_views IS NOT NULL AND views < max_views
+                RETURNING *;",
+        )?;
+        Ok(stmt.query_row((key,), content_from_row).optional()?)
+    }
+
+    fn list_content_info(&self) -> Result<Vec<Content>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare("SELECT * FROM content;")?;
+        let rows = stmt.query_map((), content_from_row)?;
+        Ok(rows.collect::<rusqlite::Result<Vec<Content>>>()?)
+    }
+
+    fn search_content_info(&self, query: &ContentQuery) -> Result<(Vec<Content>, u64)> {
+        let sql = query.to_sql(|i| format!("?{}", i));
+        let conn = self.pool.get()?;
+        let total = conn.query_row(
+            &format!("SELECT COUNT(*) FROM content {};", sql.filter),
+            params_from_iter(sql.filter_params.iter().map(sql_value)),
+            |row| row.get(0),
+        )?;
+        let mut stmt = conn.prepare(&format!(
+            "SELECT * FROM content {} {};",
+            sql.filter, sql.page
+        ))?;
+        let params = sql.filter_params.iter().chain(&sql.page_params);
+        let rows = stmt.query_map(params_from_iter(params.map(sql_value)), content_from_row)?;
+        Ok((rows.collect::<rusqlite::Result<Vec<Content>>>()?, total))
+    }
+
+    fn delete_expired(&self, now: i64) -> Result<Vec<String>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "DELETE FROM content
+                WHERE (expiry IS NOT NULL AND expiry <= ?1)
+                    OR (max_views IS NOT NULL AND views >= max_views)
+                RETURNING key;",
+        )?;
+        let rows = stmt.query_map((now,), |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
+    fn delete_inactive(&self, before: i64) -> Result<Vec<String>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "DELETE FROM content WHERE COALESCE(last_accessed, last_modified) < ?1 RETURNING key;",
+        )?;
+        let rows = stmt.query_map((before,), |row| row.get(0))?;
+        Ok(rows.collect::<rusqlite::Result<Vec<String>>>()?)
+    }
+
+    fn add_stats(&self, stats: &[(String, ContentStats)]) -> Result<()> {
+        let mut conn = self.pool.get()?;
+        // One transaction, so a batch only has to be synced once
+        let tx = conn.transaction()?;
+        {
+            let mut stmt = tx.prepare(
+                "UPDATE content SET
+                    view_count = view_count + ?2,
+                    bytes_served = bytes_served + ?3,
+                    last_accessed = COALESCE(MAX(last_accessed, ?4), last_accessed, ?4)
+                    WHERE key = ?1;",
+            )?;
+            for (key, stats) in stats {
+                stmt.execute((key, stats.views, stats.bytes_served, stats.last_accessed))?;
+            }
+        }
+        tx.commit()?;
+        Ok(())
+    }
+
+    fn get_stats(&self, key: &str) -> Result<Option<ContentStats>> {
+        let conn = self.pool.get()?;
+        let mut stmt = conn.prepare(
+            "SELECT view_count, bytes_served, last_accessed FROM content WHERE key = ?1;",
+        )?;
+        Ok(stmt
+            .query_row((key,), |row| {
+                Ok(ContentStats {
+                    views: row.get(0)?,
+                    bytes_served: row.get(1)?,
+                    last_accessed: row.get(2)?,
+                })
+            })
+            .optional()?)
+    }
+
+    fn total_stats(&self) -> Result<TotalStats> {
+        let conn = self.pool.get()?;
+        Ok(conn.query_row(
+            "SELECT COUNT(*),
+                COALESCE(SUM(content_length), 0),
+                COALESCE(SUM(view_count), 0),
+                COALESCE(SUM(bytes_served), 0)
+                FROM content;",
+            (),
+            |row| {
+                Ok(TotalStats {
+                    pastes: row.get(0)?,
+                    content_length: row.get(1)?,
+                    views: row.get(2)?,
+                    bytes_served: row.get(3)?,
+                })
+            },
+        )?)
+    }
+
+    fn checkpoint(&self) -> Result<()> {
+        if self.wal {
+            // TRUNCATE also resets the log, so it doesn't stay at its largest size forever
+            self.pool
+                .get()?
+                .pragma_update(None, "wal_checkpoint", "TRUNCATE")?;
+        }
+        Ok(())
+    }
+}
+
+fn sql_value(param: &Param) -> Value {
+    match param {
+        Param::Int(value) => Value::Integer(*value),
+        Param::Text(value) => Value::Text(value.clone()),
+    }
+}
+
+fn content_from_row(row: &Row) -> rusqlite::Result<Content> {
+    Ok(Content {
+        key: row.get(0)?,
+        content_type: row.get(1)?,
+        expiry: row.get(2)?,
+        last_modified: row.get(3)?,
+        modifiable: false,
+        auth_key: None,
+        content_encoding: row.get(4)?,
+        backend_id: row.get(5)?,
+        content_length: row.get(6)?,
+        content: None,
+        max_views: row.get(7)?,
+        views: row.get(8)?,
+        password_hash: row.get(9)?,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn store(dir: &tempfile::TempDir) -> SqliteStore {
+        let config = DatabaseConfig {
+            path: dir.path().join("bitbin.db").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        SqliteStore::open(&config).unwrap().0
+    }
+
+    #[test]
+    fn pragma_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let config = DatabaseConfig {
+            path: dir.path().join("bitbin.db").to_string_lossy().to_string(),
+            pool_size: 2,
+            busy_timeout: 1234,
+            synchronous: "FULL".to_string(),
+            ..Default::default()
+        };
+        let store = SqliteStore::open(&config).unwrap().0;
+        // Every connection gets them, not just the first one
+        let conns = [store.pool.get().unwrap(), store.pool.get().unwrap()];
+        for conn in &conns {
+            let pragma = |name: &str| -> Value {
+                conn.pragma_query_value(None, name, |row| row.get(0))
+                    .unwrap()
+            };
+            assert_eq!(pragma("journal_mode"), Value::Text("wal".to_string()));
+            assert_eq!(pragma("busy_timeout"), Value::Integer(1234));
+            assert_eq!(pragma("synchronous"), Value::Integer(2));
+        }
+
+        let dir = tempfile::tempdir().unwrap();
+        let config = DatabaseConfig {
+            path: dir.path().join("bitbin.db").to_string_lossy().to_string(),
+            wal: false,
+            ..Default::default()
+        };
+        let store = SqliteStore::open(&config).unwrap().0;
+        let journal_mode: String = store
+            .pool
+            .get()
+            .unwrap()
+            .pragma_query_value(None, "journal_mode", |row| row.get(0))
+            .unwrap();
+        assert_eq!(journal_mode, "delete");
+        store.checkpoint().unwrap();
+    }
+
+    #[test]
+    fn checkpoint_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        for i in 0..20 {
+            store
+                .save_content_info(&Content::for_tests(&format!("key{}", i)))
+                .unwrap();
+        }
+        let wal_len = || {
+            fs::metadata(dir.path().join("bitbin.db-wal"))
+                .unwrap()
+                .len()
+        };
+        assert!(wal_len() > 0);
+
+        store.checkpoint().unwrap();
+        assert_eq!(wal_len(), 0);
+        assert_eq!(store.list_content_info().unwrap().len(), 20);
+    }
+
+    #[test]
+    fn record_view_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        store
+            .save_content_info(&Content {
+                max_views: Some(2),
+                ..Content::for_tests("limited")
+            })
+            .unwrap();
+        store
+            .save_content_info(&Content::for_tests("unlimited"))
+            .unwrap();
+
+        assert_eq!(store.record_view("limited").unwrap().unwrap().views, 1);
+        assert_eq!(store.record_view("limited").unwrap().unwrap().views, 2);
+        assert!(store.record_view("limited").unwrap().is_none());
+        assert!(store.record_view("unlimited").unwrap().is_none());
+        assert!(store.record_view("missing").unwrap().is_none());
+
+        assert_eq!(store.delete_expired(0).unwrap(), vec!["limited"]);
+        assert!(store.get_content_info("unlimited").unwrap().is_some());
+    }
+
+    #[test]
+    fn stats_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        store.save_content_info(&Content::for_tests("abc")).unwrap();
+        store.save_content_info(&Content::for_tests("def")).unwrap();
+
+        let view = |views, last_accessed| ContentStats {
+            views,
+            bytes_served: views * 100,
+            last_accessed: Some(last_accessed),
+        };
+        store
+            .add_stats(&[
+                ("abc".to_string(), view(2, 1721160520000)),
+                ("missing".to_string(), view(1, 1721160520000)),
+            ])
+            .unwrap();
+        store
+            .add_stats(&[("abc".to_string(), view(1, 1721160519000))])
+            .unwrap();
+        assert_eq!(
+            store.get_stats("abc").unwrap(),
+            Some(view(3, 1721160520000))
+        );
+        assert_eq!(store.get_stats("missing").unwrap(), None);
+
+        let total = store.total_stats().unwrap();
+        assert_eq!(total.pastes, 2);
+        assert_eq!(total.content_length, 314);
+        assert_eq!(total.views, 3);
+
+        // def was never viewed, so it counts from when it was created
+        assert_eq!(
+            store.delete_inactive(1721160519500).unwrap(),
+            vec!["def".to_string()]
+        );
+    }
+
+    #[test]
+    fn search_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let store = store(&dir);
+        for (key, content_type, content_length) in [
+            ("a", "text/plain", 10),
+            ("b", "text/html", 30),
+            ("c", "image/png", 50),
+            ("d", "text_x/plain", 20),
+        ] {
+            store
+                .save_content_info(&Content {
+                    content_type: content_type.to_string(),
+                    content_length,
+                    ..Content::for_tests(key)
+                })
+                .unwrap();
+        }
+        let keys = |query: ContentQuery| {
+            let (content, total) = store.search_content_info(&query).unwrap();
+            let keys: Vec<_> = content.into_iter().map(|content| content.key).collect();
+            (keys, total)
+        };
+
+        let query = ContentQuery {
+            content_type: Some("text/*".to_string()),
+            sort: Some("content_length".to_string()),
+            desc: true,
+            ..Default::default()
+        };
+        assert_eq!(keys(query.clone()), (vec!["b".into(), "a".into()], 2));
+        let query = ContentQuery {
+            limit: Some(1),
+            offset: 1,
+            ..query
+        };
+        assert_eq!(keys(query), (vec!["a".into()], 2));
+
+        let query = ContentQuery {
+            min_size: Some(20),
+            max_size: Some(50),
+            sort: Some("key".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(keys(query), (vec!["b".into(), "c".into(), "d".into()], 3));
+    }
+}
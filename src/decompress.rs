@@ -0,0 +1,180 @@
+use std::{
+    io::{self, prelude::*, Cursor},
+    pin::Pin,
+    sync::Arc,
+    task::{Context, Poll},
+};
+
+use actix_web::{
+    body::{BodySize, MessageBody},
+    web::Bytes,
+};
+use flate2::read::MultiGzDecoder;
+use log::warn;
+
+use crate::{config::ContentConfig, stats::PendingStats, MB_LEN};
+
+/// How much is decompressed at a time when serving content.
+const CHUNK_LEN: usize = 64 * 1024;
+
+/// How large a gzipped upload of `compressed_len` bytes may get once it's decompressed.
+pub fn upload_limit(config: &ContentConfig, compressed_len: usize) -> u64 {
+    let max_size = serving_limit(config);
+    match config.max_compression_ratio {
+        0 => max_size,
+        ratio => max_size.min(compressed_len as u64 * ratio as u64),
+    }
+}
+
+/// How large stored content may get when it's decompressed for a client. The ratio isn't used,
+/// as content we gzipped ourselves can compress far better than clients are allowed to.
+pub fn serving_limit(config: &ContentConfig) -> u64 {
+    (config.max_decompressed_size * MB_LEN) as u64
+}
+
+/// Checks that `content` is well-formed gzip, returning false if it's larger than `limit` bytes
+/// decompressed. Nothing is kept, so this doesn't need more memory than a small buffer.
+pub fn validate(content: &[u8], limit: u64) -> io::Result<bool> {
+    let mut decoder = MultiGzDecoder::new(content).take(limit + 1);
+    let len = io::copy(&mut decoder, &mut io::sink())?;
+    Ok(len <= limit)
+}
+
+fn too_large() -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, "Decompressed content too large")
+}
+
+/// Decompresses gzipped content as it's sent, rather than all at once, stopping at `limit` bytes.
+/// The view is only counted once all of it has been sent, so aborted responses aren't counted.
+pub struct GzipBody {
+    decoder: MultiGzDecoder<Cursor<Vec<u8>>>,
+    key: String,
+    limit: u64,
+    sent: u64,
+    done: bool,
+    stats: Option<Arc<PendingStats>>,
+    requested: i64,
+}
+
+impl GzipBody {
+    pub fn new(
+        content: Vec<u8>,
+        key: &str,
+        limit: u64,
+        stats: Option<Arc<PendingStats>>,
+        requested: i64,
+    ) -> GzipBody {
+        GzipBody {
+            decoder: MultiGzDecoder::new(Cursor::new(content)),
+            key: key.to_string(),
+            limit,
+            sent: 0,
+            done: false,
+            stats,
+            requested,
+        }
+    }
+
+    fn next_chunk(&mut self) -> io::Result<Option<Bytes>> {
+        let mut chunk = vec![0; CHUNK_LEN];
+        let len = self.decoder.read(&mut chunk)?;
+        if len == 0 {
+            return Ok(None);
+        }
+        if self.sent + len as u64 > self.limit {
+            return Err(too_large());
+        }
+        self.sent += len as u64;
+        chunk.truncate(len);
+        Ok(Some(Bytes::from(chunk)))
+    }
+}
+
+impl MessageBody for GzipBody {
+    type Error = io::Error;
+
+    fn size(&self) -> BodySize {
+        BodySize::Stream
+    }
+
+    fn poll_next(
+        self: Pin<&mut Self>,
+        _: &mut Context<'_>,
+    ) -> Poll<Option<Result<Bytes, Self::Error>>> {
+        let body = self.get_mut();
+        if body.done {
+            return Poll::Ready(None);
+        }
+        // Chunks are small enough to decompress without blocking for long
+        match body.next_chunk() {
+            Ok(Some(chunk)) => Poll::Ready(Some(Ok(chunk))),
+            Ok(None) => {
+                body.done = true;
+                if let Some(stats) = body.stats.take() {
+                    stats.record_view(&body.key, body.sent, body.requested);
+                }
+                Poll::Ready(None)
+            }
+            Err(err) => {
+                // The headers are already sent, so all we can do is cut the response short
+                warn!("Stopped decompressing paste {}: {}", body.key, err);
+                body.done = true;
+                Poll::Ready(Some(Err(err)))
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::body;
+    use flate2::{write::GzEncoder, Compression};
+
+    fn gzip(content: &[u8]) -> Vec<u8> {
+        let mut gz = GzEncoder::new(Vec::new(), Compression::default());
+        gz.write_all(content).unwrap();
+        gz.finish().unwrap()
+    }
+
+    #[test]
+    fn validate_test() {
+        let zeros = gzip(&[0; 100_000]);
+        assert!(validate(&zeros, 100_000).unwrap());
+        assert!(!validate(&zeros, 99_999).unwrap());
+
+        // Concatenated members are valid, trailing garbage isn't
+        let mut members = gzip(b"hello ");
+        members.extend(gzip(b"world"));
+        assert!(validate(&members, 11).unwrap());
+        members.extend(b"garbage");
+        assert!(validate(&members, 100).is_err());
+        assert!(validate(b"not gzip", 100).is_err());
+
+        let config = ContentConfig {
+            max_decompressed_size: 1,
+            max_compression_ratio: 10,
+            ..Default::default()
+        };
+        assert_eq!(upload_limit(&config, 1000), 10_000);
+        assert_eq!(upload_limit(&config, 1_000_000), MB_LEN as u64);
+        assert_eq!(serving_limit(&config), MB_LEN as u64);
+    }
+
+    #[actix_web::test]
+    async fn gzip_body_test() {
+        let content: Vec<u8> = (0..200_000u32).map(|i| (i % 251) as u8).collect();
+        let stats = Arc::new(PendingStats::default());
+        let body = GzipBody::new(gzip(&content), "abc", 200_000, Some(stats.clone()), 1000);
+        assert_eq!(body::to_bytes(body).await.unwrap(), content);
+        assert_eq!(stats.get("abc").unwrap().bytes_served, 200_000);
+
+        // Responses that are cut short aren't views
+        let body = GzipBody::new(gzip(&content), "def", 100_000, Some(stats.clone()), 1000);
+        assert!(body::to_bytes(body).await.is_err());
+        // Like a client that goes away halfway through
+        let body = GzipBody::new(gzip(&content), "def", 200_000, Some(stats.clone()), 1000);
+        assert!(body::to_bytes_limited(body, 100_000).await.is_err());
+        assert!(stats.get("def").is_none());
+    }
+}
@@ -1,15 +1,33 @@
 use actix_web::{
-    dev::ServiceResponse, error::ErrorInternalServerError, middleware::ErrorHandlerResponse, Result,
+    dev::ServiceResponse, http::header::ContentType, middleware::ErrorHandlerResponse, HttpMessage,
+    HttpResponse, Result,
 };
 use log::error;
 
+use crate::access_log::RequestId;
+
 pub fn handle_500<B>(res: ServiceResponse<B>) -> Result<ErrorHandlerResponse<B>> {
     let err = get_err_str(&res);
     if let Some(str) = &err {
-        error!("{}", str);
+        let req = res.request();
+        let id = req.extensions().get::<RequestId>().map(|id| id.0.clone());
+        error!(
+            request_id = id.as_deref();
+            "{} {} failed (id={}): {}",
+            req.method(),
+            req.path(),
+            id.as_deref().unwrap_or("-"),
+            str
+        );
     }
 
-    Err(ErrorInternalServerError("Server error"))
+    // Returned as a response rather than an error, so the request ID header is still added
+    let res = res.into_response(
+        HttpResponse::InternalServerError()
+            .content_type(ContentType::plaintext())
+            .body("Server error"),
+    );
+    Ok(ErrorHandlerResponse::Response(res.map_into_right_body()))
 }
 
 fn get_err_str<B>(res: &ServiceResponse<B>) -> Option<String> {
@@ -0,0 +1,394 @@
+use std::collections::HashMap;
+
+use anyhow::{anyhow, bail, Result};
+use log::{error, info, warn};
+
+use crate::{
+    db::{self, Content, Metadata},
+    storage::{LocalStorage, ReadError, StorageBackend},
+};
+
+/// What to do about the problems found.
+#[derive(Clone, Copy, PartialEq)]
+pub enum Repair {
+    /// Only report problems
+    No,
+    /// Fix problems, moving unusable files out of the way
+    Quarantine,
+    /// Fix problems, deleting unusable files
+    Delete,
+}
+
+#[derive(Default)]
+struct Summary {
+    checked_rows: usize,
+    checked_files: usize,
+    problems: usize,
+    repaired: usize,
+    failed_repairs: usize,
+}
+
+impl Summary {
+    fn repaired(&mut self, result: Result<String>) {
+        match result {
+            Ok(action) => {
+                info!("  -> {}", action);
+                self.repaired += 1;
+            }
+            Err(err) => {
+                error!("  -> Repair failed: {}", err);
+                self.failed_repairs += 1;
+            }
+        }
+    }
+}
+
+/// Checks that the database and storage agree with each other, optionally fixing any problems.
+///
+/// - Rows without a file are deleted.
+/// - Damaged files, or ones whose key doesn't match their filename, are quarantined or deleted.
+/// - Files that can't be read for other reasons, like a missing encryption key, are left alone.
+/// - Files without a row, or whose row doesn't match, are (re-)indexed.
+/// - Temporary files left behind by interrupted writes are quarantined or deleted.
+pub async fn fsck(metadata: &Metadata, storage: &LocalStorage, repair: Repair) -> Result<()> {
+    let fix = repair != Repair::No;
+    let mut summary = Summary::default();
+
+    let mut rows: HashMap<String, Content> = db::list_content_info(metadata)
+        .await?
+        .into_iter()
+        .map(|row| (row.key.clone(), row))
+        .collect();
+    summary.checked_rows = rows.len();
+
+    let keys = match storage.list_keys() {
+        Ok(keys) => keys,
+        Err(err) => bail!("Failed to list content: {}", err),
+    };
+    summary.checked_files = keys.len();
+
+    info!(
+        "Checking {} rows in {} against {} files in {} storage...",
+        summary.checked_rows,
+        metadata.backend_id(),
+        summary.checked_files,
+        storage.backend_id()
+    );
+
+    for key in keys {
+        let row = rows.remove(&key);
+
+        let content = match storage.check_content(&key) {
+            Ok(content) => content,
+            Err(ReadError::Corrupt(err)) => {
+                summary.problems += 1;
+                warn!("Paste {} is damaged: {}", key, err);
+                if fix {
+                    summary.repaired(remove(metadata, storage, &key, row.is_some(), repair).await);
+                }
+                continue;
+            }
+            Err(ReadError::Unavailable(err)) => {
+                // Likely a problem with the setup rather than the file, so it's never removed
+                summary.problems += 1;
+                warn!("Paste {} can't be read: {}", key, err);
+                if fix {
+                    summary.repaired(Err(anyhow!(
+                        "Left alone, check the storage permissions and encryption keys"
+                    )));
+                }
+                continue;
+            }
+        };
+
+        if content.key != key {
+            summary.problems += 1;
+            warn!(
+                "Paste {} has the key '{}' in its header, which doesn't match its filename",
+                key, content.key
+            );
+            if fix {
+                summary.repaired(remove(metadata, storage, &key, row.is_some(), repair).await);
+            }
+            continue;
+        }
+
+        match row {
+            None => {
+                summary.problems += 1;
+                warn!("Paste {} is missing from the database", key);
+                if fix {
+                    summary.repaired(reindex(metadata, content, false).await);
+                }
+            }
+            Some(row) if !matches(&row, &content) => {
+                summary.problems += 1;
+                warn!(
+                    "Paste {} doesn't match the database (content_type: {} / {}, encoding: {} / {}, length: {} / {}, last_modified: {} / {})",
+                    key,
+                    row.content_type,
+                    content.content_type,
+                    row.content_encoding,
+                    content.content_encoding,
+                    row.content_length,
+                    content.content_length,
+                    row.last_modified,
+                    content.last_modified
+                );
+                if fix {
+                    summary.repaired(reindex(metadata, content, true).await);
+                }
+            }
+            Some(_) => {}
+        }
+    }
+
+    let leftovers = match storage.list_leftovers() {
+        Ok(leftovers) => leftovers,
+        Err(err) => bail!("Failed to list content: {}", err),
+    };
+    for name in leftovers {
+        summary.problems += 1;
+        warn!("{} was left behind by an interrupted write", name);
+        if fix {
+            summary.repaired(remove(metadata, storage, &name, false, repair).await);
+        }
+    }
+
+    // Everything left doesn't have a file
+    for key in rows.into_keys() {
+        summary.problems += 1;
+        warn!("Paste {} is in the database, but has no file", key);
+        if fix {
+            let result = db::delete_content_info(metadata, key)
+                .await
+                .map(|_| "Deleted database row".to_string());
+            summary.repaired(result);
+        }
+    }
+
+    info!(
+        "Checked {} rows and {} files. Found {} problems, repaired {}, failed to repair {}.",
+        summary.checked_rows,
+        summary.checked_files,
+        summary.problems,
+        summary.repaired,
+        summary.failed_repairs
+    );
+
+    if !fix && summary.problems > 0 {
+        bail!(
+            "Found {} problems! Run with --repair to fix them.",
+            summary.problems
+        );
+    }
+    if summary.failed_repairs > 0 {
+        bail!("Failed to repair {} problems", summary.failed_repairs);
+    }
+    Ok(())
+}
+
+fn matches(row: &Content, content: &Content) -> bool {
+    row.content_type == content.content_type
+        && row.content_encoding == content.content_encoding
+        && row.content_length == content.content_length
+        && row.last_modified == content.last_modified
+        && row.expiry == content.expiry
+        && row.max_views == content.max_views
+        && row.password_hash == content.password_hash
+        && row.backend_id == content.backend_id
+}
+
+async fn remove(
+    metadata: &Metadata,
+    storage: &LocalStorage,
+    key: &str,
+    has_row: bool,
+    repair: Repair,
+) -> Result<String> {
+    let action = if repair == Repair::Delete {
+        storage
+            .delete_content(key)
+            .map(|_| "Deleted file".to_string())
+    } else {
+        storage
+            .quarantine_content(key)
+            .map(|path| format!("Quarantined to {}", path.to_string_lossy()))
+    };
+    let action = match action {
+        Ok(action) => action,
+        Err(err) => bail!("{}", err),
+    };
+    if has_row {
+        db::delete_content_info(metadata, key.to_string()).await?;
+    }
+    Ok(action)
+}
+
+async fn reindex(metadata: &Metadata, mut content: Content, has_row: bool) -> Result<String> {
+    content.content = None;
+    if has_row {
+        db::delete_content_info(metadata, content.key.clone()).await?;
+    }
+    db::save_content_info(metadata, &content).await?;
+    Ok("Re-indexed from storage".to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{fs, path::Path};
+
+    use super::*;
+    use crate::{
+        config::{DatabaseConfig, StorageConfig},
+        crypto::Keyring,
+    };
+
+    async fn open(dir: &Path, keyring: Keyring) -> (Metadata, LocalStorage) {
+        let config = DatabaseConfig {
+            path: dir.join("bitbin.db").to_string_lossy().to_string(),
+            ..Default::default()
+        };
+        let storage = LocalStorage::new(dir.join("content"), keyring, true);
+        storage.initialize().unwrap();
+        (db::open(&config).await.unwrap().0, storage)
+    }
+
+    /// Saves a paste to both the database and storage.
+    async fn save(metadata: &Metadata, storage: &LocalStorage, key: &str) {
+        let content = Content {
+            content_length: 5,
+            content: Some(b"hello".to_vec()),
+            ..Content::for_tests(key)
+        };
+        storage.save_content(&content).unwrap();
+        db::save_content_info(metadata, &content).await.unwrap();
+    }
+
+    async fn has_row(metadata: &Metadata, key: &str) -> bool {
+        db::get_content_info(metadata, key.to_string())
+            .await
+            .unwrap()
+            .is_some()
+    }
+
+    #[actix_web::test]
+    async fn healthy_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (metadata, storage) = open(dir.path(), Keyring::empty()).await;
+        save(&metadata, &storage, "abc").await;
+        assert!(fsck(&metadata, &storage, Repair::No).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn missing_file_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (metadata, storage) = open(dir.path(), Keyring::empty()).await;
+        save(&metadata, &storage, "abc").await;
+        fs::remove_file(storage.path.join("abc")).unwrap();
+
+        assert!(fsck(&metadata, &storage, Repair::No).await.is_err());
+        assert!(has_row(&metadata, "abc").await);
+        assert!(fsck(&metadata, &storage, Repair::Quarantine).await.is_ok());
+        assert!(!has_row(&metadata, "abc").await);
+    }
+
+    #[actix_web::test]
+    async fn missing_row_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (metadata, storage) = open(dir.path(), Keyring::empty()).await;
+        save(&metadata, &storage, "abc").await;
+        db::delete_content_info(&metadata, "abc".to_string())
+            .await
+            .unwrap();
+
+        assert!(fsck(&metadata, &storage, Repair::No).await.is_err());
+        assert!(fsck(&metadata, &storage, Repair::Quarantine).await.is_ok());
+        let row = db::get_content_info(&metadata, "abc".to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(row.content_length, 5);
+        assert!(fsck(&metadata, &storage, Repair::No).await.is_ok());
+    }
+
+    #[actix_web::test]
+    async fn key_mismatch_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (metadata, storage) = open(dir.path(), Keyring::empty()).await;
+        save(&metadata, &storage, "abc").await;
+        fs::copy(storage.path.join("abc"), storage.path.join("def")).unwrap();
+
+        assert!(fsck(&metadata, &storage, Repair::No).await.is_err());
+        assert!(fsck(&metadata, &storage, Repair::Quarantine).await.is_ok());
+        assert!(storage.path.join(".quarantine/def").exists());
+        assert!(!storage.path.join("def").exists());
+        assert!(storage.path.join("abc").exists());
+    }
+
+    #[actix_web::test]
+    async fn checksum_test() {
+        for repair in [Repair::No, Repair::Quarantine, Repair::Delete] {
+            let dir = tempfile::tempdir().unwrap();
+            let (metadata, storage) = open(dir.path(), Keyring::empty()).await;
+            save(&metadata, &storage, "abc").await;
+            let path = storage.path.join("abc");
+            let mut raw = fs::read(&path).unwrap();
+            let len = raw.len();
+            raw[len - 5] ^= 1; // Last byte of the content
+            fs::write(&path, raw).unwrap();
+
+            let result = fsck(&metadata, &storage, repair).await;
+            let quarantined = storage.path.join(".quarantine/abc").exists();
+            match repair {
+                Repair::No => {
+                    assert!(result.is_err());
+                    assert!(path.exists() && has_row(&metadata, "abc").await);
+                }
+                Repair::Quarantine => {
+                    assert!(result.is_ok());
+                    assert!(!path.exists() && quarantined);
+                    assert!(!has_row(&metadata, "abc").await);
+                }
+                Repair::Delete => {
+                    assert!(result.is_ok());
+                    assert!(!path.exists() && !quarantined);
+                    assert!(!has_row(&metadata, "abc").await);
+                }
+            }
+        }
+    }
+
+    #[actix_web::test]
+    async fn leftovers_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let (metadata, storage) = open(dir.path(), Keyring::empty()).await;
+        save(&metadata, &storage, "abc").await;
+        fs::write(storage.path.join(".def.tmp"), b"partial").unwrap();
+
+        assert!(fsck(&metadata, &storage, Repair::No).await.is_err());
+        assert!(fsck(&metadata, &storage, Repair::Delete).await.is_ok());
+        assert!(!storage.path.join(".def.tmp").exists());
+        assert!(storage.path.join("abc").exists());
+    }
+
+    #[actix_web::test]
+    async fn unknown_key_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let keyring = Keyring::from_config(&StorageConfig {
+            encryption: true,
+            encryption_key: Some("42".repeat(32)),
+            encryption_key_id: 1,
+            ..Default::default()
+        })
+        .unwrap();
+        let (metadata, storage) = open(dir.path(), keyring).await;
+        save(&metadata, &storage, "abc").await;
+
+        // Opened without the key, like with a misconfigured keyring
+        let (metadata, storage) = open(dir.path(), Keyring::empty()).await;
+        assert!(fsck(&metadata, &storage, Repair::Delete).await.is_err());
+        assert!(storage.path.join("abc").exists());
+        assert!(has_row(&metadata, "abc").await);
+    }
+}
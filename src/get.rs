@@ -1,23 +1,29 @@
 use actix_web::{
     error::{ErrorInternalServerError, ErrorNotAcceptable, ErrorNotFound},
     get,
-    http::header::{self, ContentEncoding},
-    web::{self, Bytes, Data},
+    http::header::{self, ContentDisposition, ContentEncoding},
+    web::Data,
     Error, HttpRequest, HttpResponse, Responder,
 };
 use anyhow::Result;
-use flate2::read::GzDecoder;
-use log::warn;
-use std::io::prelude::*;
+use log::{error, warn};
+use std::time::SystemTime;
 
 use crate::{
+    content_type,
     db::{self, Content},
-    State,
+    decompress::{self, GzipBody},
+    keys::VANITY_SEPARATOR,
+    password, storage, telemetry, State,
 };
 
 const CACHE_CONTROL_STATIC: &str = "public, max-age=604800, no-transform, immutable";
 #[allow(dead_code)]
 const CACHE_CONTROL_DYNAMIC: &str = "public, no-cache, proxy-revalidate, no-transform";
+const CACHE_CONTROL_NO_STORE: &str = "no-store";
+/// Keeps content from loading anything or running scripts, even if it's opened directly.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; style-src 'unsafe-inline'; sandbox";
+const TEXT_PLAIN: &str = "text/plain; charset=utf-8";
 
 #[get("/{key}")]
 pub async fn get(state: Data<State>, req: HttpRequest) -> Result<impl Responder, Error> {
@@ -32,56 +38,144 @@ pub async fn get(state: Data<State>, req: HttpRequest) -> Result<impl Responder,
     if !validate_path(key) {
         return Err(ErrorNotFound("Invalid path"));
     }
+    telemetry::record_key(key);
 
-    let content = match db::get_content_info(&state.pool, key.to_string()).await {
+    let content = find(&state, key).await?;
+    if let Some(hash) = &content.password_hash {
+        let config = state.config.load();
+        password::check(&req, &state.password_failures, &config.content, key, hash).await?;
+    }
+    serve(&state, &req, key, content).await
+}
+
+/// The info of the content with `key`, which has to be validated already.
+pub async fn find(state: &State, key: &str) -> Result<Content, Error> {
+    let content = match db::get_content_info(&state.metadata, key.to_string()).await {
         Ok(Some(c)) => c,
         Ok(None) => return Err(ErrorNotFound("Invalid path")),
         Err(err) => return Err(ErrorInternalServerError(err)),
     };
 
+    // Expired content is only deleted periodically, so it may still be around.
+    if content.expiry.is_some_and(|expiry| expiry <= now_millis()) {
+        return Err(ErrorNotFound("Invalid path"));
+    }
+    Ok(content)
+}
+
+/// Responds with `content`, once the client is allowed to see it.
+pub async fn serve(
+    state: &State,
+    req: &HttpRequest,
+    key: &str,
+    mut content: Content,
+) -> Result<HttpResponse, Error> {
+    let config = state.config.load();
+
+    // Checked before counting a view, so one isn't used up without getting the content
+    let accept_encoding = get_accepted_encoding(req);
+    let accepted = accepts_encoding(&content, &accept_encoding);
+    if !accepted && content.content_encoding != ContentEncoding::Gzip.as_str() {
+        return Err(ErrorNotAcceptable(format!(
+            "Accept-Encoding \"{}\" does not contain Content-Encoding \"{}\"",
+            accept_encoding, content.content_encoding
+        )));
+    }
+
+    // Read before counting a view, so one isn't used up if reading fails
+    let content_data = storage::get_content(&state.storage, key.to_string(), false)
+        .await?
+        .content
+        .unwrap();
+
+    let limited = content.max_views.is_some();
+    if limited {
+        content = match db::record_view(&state.metadata, key.to_string()).await {
+            Ok(Some(c)) => c,
+            // Someone else got the last view
+            Ok(None) => return Err(ErrorNotFound("Invalid path")),
+            Err(err) => return Err(ErrorInternalServerError(err)),
+        };
+    }
+
     // Once we have support for modifying existing content, we'll have to return the dynamic
     // variant of this for modifiable content, while returning the current one for static content.
     // https://github.com/lucko/bytebin/blob/9ac4aef610c3aa6215f17c7af78568908659d7b6/src/main/java/me/lucko/bytebin/http/GetHandler.java#L100-L114
-    let cache_control = CACHE_CONTROL_STATIC;
+    let cache_control = if limited || content.password_hash.is_some() {
+        // Every view has to reach us to be counted or checked
+        CACHE_CONTROL_NO_STORE
+    } else {
+        CACHE_CONTROL_STATIC
+    };
 
-    let content_data = state.storage.get_content(key, false)?.content.unwrap();
+    if content
+        .max_views
+        .is_some_and(|max_views| content.views >= max_views)
+    {
+        delete_viewed(state, key).await;
+    }
 
     let mut res = HttpResponse::Ok();
     res.insert_header((header::LAST_MODIFIED, content.last_modified));
-    res.insert_header((header::CONTENT_TYPE, content.content_type.clone()));
     res.insert_header((header::CACHE_CONTROL, cache_control));
+    res.insert_header((header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY));
+    if !content_type::is_active(&content.content_type) {
+        res.insert_header((header::CONTENT_TYPE, content.content_type.clone()));
+    } else if config.content.active_types_as_text {
+        res.insert_header((header::CONTENT_TYPE, TEXT_PLAIN));
+    } else {
+        res.insert_header((header::CONTENT_TYPE, content.content_type.clone()));
+        res.insert_header(ContentDisposition::attachment(key));
+    }
 
-    let accept_encoding = get_accepted_encoding(&req);
-    if accepts_encoding(&content, &accept_encoding) {
+    if accepted {
+        if config.stats.enabled {
+            state
+                .stats
+                .record_view(key, content_data.len() as u64, now_millis());
+        }
         return Ok(res
             .insert_header((header::CONTENT_ENCODING, content.content_encoding))
             .body(content_data));
     }
 
-    if content.content_encoding == ContentEncoding::Gzip.as_str() {
-        warn!("[REQUEST] Request for 'key = {}' was made with incompatible Accept-Encoding headers! Content-Encoding = {}, Accept-Encoding = {}", key, content.content_encoding, accept_encoding);
-        let content_data = web::block(move || {
-            let mut gz = GzDecoder::new(content_data.as_slice());
-            let mut s = Vec::new();
-            match gz.read_to_end(&mut s) {
-                Ok(_) => Ok(s),
-                Err(err) => Err(err),
-            }
-        })
-        .await??;
-        return Ok(res
-            .insert_header((header::CONTENT_ENCODING, ContentEncoding::Identity.as_str()))
-            .body(Bytes::from(content_data)));
+    warn!("[REQUEST] Request for 'key = {}' was made with incompatible Accept-Encoding headers! Content-Encoding = {}, Accept-Encoding = {}", key, content.content_encoding, accept_encoding);
+    let limit = decompress::serving_limit(&config.content);
+    let stats = config.stats.enabled.then(|| state.stats.clone());
+    let body = GzipBody::new(content_data, key, limit, stats, now_millis());
+    Ok(res
+        .insert_header((header::CONTENT_ENCODING, ContentEncoding::Identity.as_str()))
+        .body(body))
+}
+
+/// Deletes content that just had its last view.
+/// The row is left alone if the file can't be deleted, so the expiry sweep tries again.
+async fn delete_viewed(state: &State, key: &str) {
+    if let Err(err) = storage::delete_content(&state.storage, key.to_string()).await {
+        error!(
+            "Failed to delete paste {} after its last view: {}",
+            key, err
+        );
+        return;
+    }
+    if let Err(err) = db::delete_content_info(&state.metadata, key.to_string()).await {
+        error!(
+            "Failed to delete paste {} after its last view: {}",
+            key, err
+        );
     }
+}
 
-    Err(ErrorNotAcceptable(format!(
-        "Accept-Encoding \"{}\" does not contain Content-Encoding \"{}\"",
-        accept_encoding, content.content_encoding
-    )))
+fn now_millis() -> i64 {
+    SystemTime::now()
+        .duration_since(SystemTime::UNIX_EPOCH)
+        .map(|d| d.as_millis() as i64)
+        .unwrap_or_default()
 }
 
-fn validate_path(path: &str) -> bool {
-    return path.chars().all(|c| c.is_ascii_alphanumeric());
+pub fn validate_path(path: &str) -> bool {
+    path.chars()
+        .all(|c| c.is_ascii_alphanumeric() || c == VANITY_SEPARATOR)
 }
 
 fn get_accepted_encoding(req: &HttpRequest) -> String {
@@ -119,10 +213,124 @@ fn accepts_encoding(content: &Content, accept_encoding: &str) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::{config::Config, post};
+    use actix_web::{
+        http::StatusCode,
+        test::{self, TestRequest},
+        App,
+    };
+    use std::rc::Rc;
+
+    #[actix_web::test]
+    async fn identity_round_trip_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Data::new(State::for_tests(dir.path(), Config::default()).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(post::post)
+                .service(get),
+        )
+        .await;
+
+        // Compresses far better than the ratio allowed for gzipped uploads
+        let content = "2026-10-18 12:00:00 INFO Request handled\n".repeat(27_000);
+        let req = TestRequest::post()
+            .uri("/post")
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload(content.clone())
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let key = res
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let stored = db::get_content_info(&state.metadata, key.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        let ratio = state.config.load().content.max_compression_ratio as usize;
+        assert!(stored.content_length * ratio < content.len());
+
+        let req = TestRequest::get().uri(&format!("/{}", key)).to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        assert_eq!(body.len(), content.len());
+        assert_eq!(body, content.as_bytes());
+    }
+
+    #[actix_web::test]
+    async fn max_views_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Data::new(State::for_tests(dir.path(), Config::default()).await);
+        let app = Rc::new(
+            test::init_service(
+                App::new()
+                    .app_data(state.clone())
+                    .service(post::post)
+                    .service(get),
+            )
+            .await,
+        );
+
+        let req = TestRequest::post()
+            .uri("/post")
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .insert_header((post::MAX_VIEWS_HEADER, "3"))
+            .set_payload("only three of you get to see this")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let key = res
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+
+        let requests: Vec<_> = (0..20)
+            .map(|_| {
+                let app = app.clone();
+                let uri = format!("/{}", key);
+                actix_web::rt::spawn(async move {
+                    let req = TestRequest::get()
+                        .uri(&uri)
+                        .insert_header((header::ACCEPT_ENCODING, "gzip"))
+                        .to_request();
+                    let res = test::call_service(&app, req).await;
+                    let status = res.status();
+                    (status, test::read_body(res).await)
+                })
+            })
+            .collect();
+        let mut served = 0;
+        for request in requests {
+            let (status, body) = request.await.unwrap();
+            if status == StatusCode::OK {
+                assert!(!body.is_empty());
+                served += 1;
+            } else {
+                assert_eq!(status, StatusCode::NOT_FOUND);
+            }
+        }
+        assert_eq!(served, 3);
+
+        assert!(db::get_content_info(&state.metadata, key.clone())
+            .await
+            .unwrap()
+            .is_none());
+        assert!(state.storage.get_content(&key, true).is_err());
+    }
 
     #[test]
     fn validate_path_test() {
         assert!(validate_path("abc123"));
+        assert!(validate_path("release-notes-2026"));
         assert!(!validate_path("..abc"));
         assert!(!validate_path("../abc"));
         assert!(!validate_path("abc/def"));
@@ -0,0 +1,219 @@
+use std::{
+    fs,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use anyhow::{bail, Result};
+use regex_lite::Regex;
+
+use crate::config::MiscConfig;
+
+pub const KEY_STRATEGIES: &[&str] = &["random", "unambiguous", "pronounceable", "words"];
+/// Alphanumerics without the ones that are easily mixed up, like 0/O and 1/l/I.
+const UNAMBIGUOUS_CHARSET: &str = "23456789abcdefghijkmnopqrstuvwxyzABCDEFGHJKLMNPQRSTUVWXYZ";
+const CONSONANTS: &str = "bcdfghjkmnpqrstvwxz";
+const VOWELS: &str = "aeiouy";
+/// How many collisions in a row it takes before keys get longer.
+const ATTEMPTS_PER_LENGTH: usize = 3;
+/// How much longer than the current length keys get before giving up.
+const MAX_EXTRA_LENGTH: usize = 4;
+/// Generated keys never contain this, so vanity keys are required to.
+pub const VANITY_SEPARATOR: char = '-';
+const MAX_VANITY_KEY_LENGTH: usize = 64;
+
+enum Strategy {
+    Charset(String),
+    Pronounceable,
+    Words(Vec<String>),
+}
+
+/// Generates keys for new content, getting longer once collisions become common.
+pub struct KeyGenerator {
+    strategy: Strategy,
+    /// In characters, or words for the words strategy
+    length: AtomicUsize,
+    vanity_pattern: Regex,
+}
+
+impl KeyGenerator {
+    pub fn from_config(config: &MiscConfig) -> Result<Self> {
+        let strategy = match config.key_strategy.as_str() {
+            "random" => Strategy::Charset(config.key_charset.clone()),
+            "unambiguous" => Strategy::Charset(UNAMBIGUOUS_CHARSET.to_string()),
+            "pronounceable" => Strategy::Pronounceable,
+            "words" => {
+                let Some(path) = &config.key_wordlist_file else {
+                    bail!("misc.key_wordlist_file must be set to use the words key strategy");
+                };
+                let words = match fs::read_to_string(path) {
+                    Ok(words) => parse_words(&words),
+                    Err(err) => bail!("Failed to read word list '{}': {}", path, err),
+                };
+                if words.len() < 2 {
+                    bail!("The word list '{}' needs at least two usable words", path);
+                }
+                Strategy::Words(words)
+            }
+            strategy => bail!("Unknown key strategy '{}'", strategy),
+        };
+        let vanity_pattern = match Regex::new(&config.vanity_key_pattern) {
+            Ok(pattern) => pattern,
+            Err(err) => bail!("Invalid vanity key pattern: {}", err),
+        };
+        Ok(Self {
+            strategy,
+            length: AtomicUsize::new(config.keylength),
+            vanity_pattern,
+        })
+    }
+
+    /// Keys to try until one isn't taken, with their lengths.
+    /// After a few collisions at one length, longer keys are tried.
+    pub fn candidates(&self) -> impl Iterator<Item = (usize, String)> + '_ {
+        let start = self.length.load(Ordering::Relaxed);
+        (0..ATTEMPTS_PER_LENGTH * (MAX_EXTRA_LENGTH + 1)).map(move |attempt| {
+            let length = start + attempt / ATTEMPTS_PER_LENGTH;
+            (length, self.generate(length))
+        })
+    }
+
+    /// Keeps keys at least `length` long from now on, once shorter ones kept colliding.
+    pub fn grow_to(&self, length: usize) {
+        self.length.fetch_max(length, Ordering::Relaxed);
+    }
+
+    /// How long new keys start out, including any growth from collisions.
+    pub fn length(&self) -> usize {
+        self.length.load(Ordering::Relaxed)
+    }
+
+    pub fn generate(&self, length: usize) -> String {
+        match &self.strategy {
+            Strategy::Charset(charset) => random_string::generate(length, charset),
+            Strategy::Pronounceable => {
+                // Alternating, starting with either
+                let offset = fastrand::usize(..2);
+                (0..length)
+                    .map(|i| {
+                        let letters = if (i + offset) % 2 == 0 {
+                            CONSONANTS
+                        } else {
+                            VOWELS
+                        };
+                        random_string::generate(1, letters)
+                    })
+                    .collect()
+            }
+            Strategy::Words(words) => (0..length)
+                .map(|_| capitalize(&words[fastrand::usize(..words.len())]))
+                .collect(),
+        }
+    }
+
+    /// Checks a key chosen by a client, returning why it can't be used.
+    pub fn check_vanity_key(&self, key: &str) -> Result<(), &'static str> {
+        if key.len() > MAX_VANITY_KEY_LENGTH {
+            return Err("Key is too long");
+        }
+        // Keys are used as file names, so this is checked no matter the pattern
+        if !key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == VANITY_SEPARATOR)
+        {
+            return Err("Key may only contain letters, digits and '-'");
+        }
+        if !key.contains(VANITY_SEPARATOR) {
+            return Err("Key must contain a '-'");
+        }
+        if !self.vanity_pattern.is_match(key) {
+            return Err("Key doesn't match the allowed pattern");
+        }
+        Ok(())
+    }
+}
+
+/// Whether both configs generate keys from the same keyspace, so lengths that keys grew to
+/// under one are still needed under the other.
+pub fn same_keyspace(a: &MiscConfig, b: &MiscConfig) -> bool {
+    a.key_strategy == b.key_strategy
+        && a.key_charset == b.key_charset
+        && a.key_wordlist_file == b.key_wordlist_file
+}
+
+/// One word per line, keeping only the ones that are valid in keys.
+fn parse_words(list: &str) -> Vec<String> {
+    let mut words: Vec<String> = list
+        .lines()
+        .map(str::trim)
+        .filter(|word| !word.is_empty() && word.chars().all(|c| c.is_ascii_alphanumeric()))
+        .map(str::to_lowercase)
+        .collect();
+    words.sort();
+    words.dedup();
+    words
+}
+
+/// Word boundaries are kept by capitalizing, as keys can't contain separators.
+fn capitalize(word: &str) -> String {
+    let mut chars = word.chars();
+    match chars.next() {
+        Some(first) => first.to_ascii_uppercase().to_string() + chars.as_str(),
+        None => String::new(),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn generator(strategy: Strategy) -> KeyGenerator {
+        KeyGenerator {
+            strategy,
+            length: AtomicUsize::new(4),
+            vanity_pattern: Regex::new(&MiscConfig::default().vanity_key_pattern).unwrap(),
+        }
+    }
+
+    #[test]
+    fn generate_test() {
+        let key = generator(Strategy::Charset(UNAMBIGUOUS_CHARSET.to_string())).generate(50);
+        assert_eq!(key.len(), 50);
+        assert!(!key.contains(['0', 'O', '1', 'l', 'I']));
+
+        let key = generator(Strategy::Pronounceable).generate(6);
+        let vowels: Vec<bool> = key.chars().map(|c| VOWELS.contains(c)).collect();
+        assert!(vowels.windows(2).all(|pair| pair[0] != pair[1]));
+
+        let words = parse_words("apple\nBanana\n\nnot-valid\napple\n");
+        assert_eq!(words, vec!["apple", "banana"]);
+        let key = generator(Strategy::Words(words)).generate(3);
+        assert!(key.chars().filter(char::is_ascii_uppercase).count() == 3);
+    }
+
+    #[test]
+    fn candidates_test() {
+        let keys = generator(Strategy::Charset("a".to_string()));
+        let lengths: Vec<usize> = keys.candidates().map(|(length, _)| length).collect();
+        assert_eq!(lengths[..7], [4, 4, 4, 5, 5, 5, 6]);
+        assert_eq!(lengths.last(), Some(&(4 + MAX_EXTRA_LENGTH)));
+
+        keys.grow_to(6);
+        keys.grow_to(5);
+        let (length, key) = keys.candidates().next().unwrap();
+        assert_eq!(length, 6);
+        assert_eq!(key, "aaaaaa");
+    }
+
+    #[test]
+    fn vanity_key_test() {
+        let keys = generator(Strategy::Pronounceable);
+        assert!(keys.check_vanity_key("release-notes-2026").is_ok());
+        assert!(keys.check_vanity_key("releasenotes").is_err());
+        assert!(keys.check_vanity_key("Release-Notes").is_err());
+        assert!(keys.check_vanity_key("-release").is_err());
+        assert!(keys.check_vanity_key("../release-notes").is_err());
+        assert!(keys
+            .check_vanity_key(&format!("a{}", "-a".repeat(40)))
+            .is_err());
+    }
+}
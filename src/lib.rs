@@ -1,11 +1,20 @@
 use proc_macro::TokenStream;
-use quote::quote;
-use syn::{parse_macro_input, Data, DeriveInput, Fields};
+use quote::{format_ident, quote};
+use syn::{parse_macro_input, Data, DeriveInput, Fields, Type};
 
-#[proc_macro_derive(CopyNonDefaults)]
-pub fn copy_non_defaults_derive(input: TokenStream) -> TokenStream {
+/// Generates a `<Name>Layer` struct where every field is optional, so it's possible to tell which
+/// values a config source actually provided, along with an `apply_layer` method to copy them over.
+///
+/// `Option` fields become `Option<Option<T>>` in the layer, where an empty string unsets them,
+/// since neither TOML nor environment variables have a null value.
+/// Fields marked with `#[layer(nested)]` are sections which derive `Layered` themselves.
+/// The field names are also available as `FIELDS`, so unknown keys can be reported.
+#[proc_macro_derive(Layered, attributes(layer))]
+pub fn layered_derive(input: TokenStream) -> TokenStream {
     let input = parse_macro_input!(input as DeriveInput);
     let name = &input.ident;
+    let vis = &input.vis;
+    let layer_name = format_ident!("{}Layer", name);
     let gen = match input.data {
         Data::Struct(ref data) => {
             let fields = match &data.fields {
@@ -14,28 +23,93 @@ pub fn copy_non_defaults_derive(input: TokenStream) -> TokenStream {
                 Fields::Unit => panic!("Unit structs are not supported"),
             };
 
-            let mut field_copies = Vec::with_capacity(fields.len());
+            let mut layer_fields = Vec::with_capacity(fields.len());
+            let mut field_applies = Vec::with_capacity(fields.len());
+            let field_names = fields
+                .iter()
+                .map(|field| field.ident.as_ref().unwrap().to_string());
 
             for field in fields {
                 let field_name = &field.ident;
+                let field_vis = &field.vis;
+                let ty = &field.ty;
 
-                field_copies.push(quote! {
-                    if other.#field_name != def.#field_name {
-                        self.#field_name = other.#field_name.clone();
+                if is_nested(field) {
+                    let layer_ty = match ty {
+                        Type::Path(path) => {
+                            let mut path = path.clone();
+                            let last = path.path.segments.last_mut().unwrap();
+                            last.ident = format_ident!("{}Layer", last.ident);
+                            path
+                        }
+                        _ => panic!("Nested fields must be structs deriving Layered"),
+                    };
+                    layer_fields.push(quote! {
+                        #field_vis #field_name: #layer_ty
+                    });
+                    field_applies.push(quote! {
+                        self.#field_name.apply_layer(&layer.#field_name);
+                    });
+                    continue;
+                }
+
+                if is_option(ty) {
+                    layer_fields.push(quote! {
+                        #[serde(deserialize_with = "crate::config::deserialize_unsettable")]
+                        #field_vis #field_name: Option<#ty>
+                    });
+                } else {
+                    layer_fields.push(quote! {
+                        #field_vis #field_name: Option<#ty>
+                    });
+                }
+                field_applies.push(quote! {
+                    if let Some(value) = &layer.#field_name {
+                        self.#field_name = value.clone();
                     }
                 });
             }
 
             quote! {
+                #[derive(::serde::Deserialize, Default, Debug)]
+                #[serde(default)]
+                #vis struct #layer_name {
+                    #(#layer_fields,)*
+                }
+
                 impl #name {
-                    pub fn copy_non_defaults(&mut self, other: &#name) {
-                        let def = Self::default();
-                        #(#field_copies)*
+                    /// The names of all fields, to detect unknown keys.
+                    #[allow(dead_code)]
+                    pub const FIELDS: &'static [&'static str] = &[#(#field_names),*];
+
+                    /// Overwrites every value that was provided in `layer`.
+                    pub fn apply_layer(&mut self, layer: &#layer_name) {
+                        #(#field_applies)*
                     }
                 }
             }
         }
-        _ => panic!("CopyNonDefaults can only be derived for structs"),
+        _ => panic!("Layered can only be derived for structs"),
     };
     gen.into()
 }
+
+fn is_nested(field: &syn::Field) -> bool {
+    field.attrs.iter().any(|attr| {
+        attr.path().is_ident("layer")
+            && attr
+                .parse_args::<syn::Ident>()
+                .is_ok_and(|ident| ident == "nested")
+    })
+}
+
+fn is_option(ty: &Type) -> bool {
+    match ty {
+        Type::Path(path) => path
+            .path
+            .segments
+            .last()
+            .is_some_and(|segment| segment.ident == "Option"),
+        _ => false,
+    }
+}
@@ -0,0 +1,355 @@
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Mutex, RwLock},
+};
+
+use anyhow::{anyhow, Result};
+use log::{kv, LevelFilter, Log, Metadata, Record};
+use serde_json::{Map, Value};
+use simplelog::{ColorChoice, TermLogger, TerminalMode, WriteLogger};
+use time::{format_description::well_known::Rfc3339, OffsetDateTime};
+
+use crate::{config::LogConfig, MB_LEN};
+
+pub const LOG_FORMATS: &[&str] = &["text", "json"];
+/// The target access log lines are logged with.
+pub const ACCESS_TARGET: &str = "access";
+
+/// Installed once, so the outputs can be replaced when the config changes.
+static LOGGER: Logger = Logger {
+    outputs: RwLock::new(Vec::new()),
+};
+
+struct Logger {
+    outputs: RwLock<Vec<Output>>,
+}
+
+struct Output {
+    filter: Filter,
+    logger: Box<dyn Log>,
+}
+
+/// Which lines an output gets.
+#[derive(Clone, Copy, PartialEq)]
+enum Filter {
+    All,
+    Access,
+    NotAccess,
+}
+
+impl Filter {
+    fn matches(self, target: &str) -> bool {
+        match self {
+            Filter::All => true,
+            Filter::Access => target == ACCESS_TARGET,
+            Filter::NotAccess => target != ACCESS_TARGET,
+        }
+    }
+}
+
+impl Log for Logger {
+    // Everything is filtered by the max level instead, so it can be changed while running
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        for output in self.outputs.read().unwrap().iter() {
+            if output.filter.matches(record.target()) {
+                output.logger.log(record);
+            }
+        }
+    }
+
+    fn flush(&self) {
+        for output in self.outputs.read().unwrap().iter() {
+            output.logger.flush();
+        }
+    }
+}
+
+/// Logs everything to the terminal until [`configure`] is called.
+pub fn init() {
+    *LOGGER.outputs.write().unwrap() = vec![Output {
+        filter: Filter::All,
+        logger: terminal_logger(),
+    }];
+    log::set_logger(&LOGGER).unwrap();
+}
+
+/// Replaces the log outputs with the ones in `config`.
+/// Log files are reopened, so they can be moved away before reloading.
+pub fn configure(config: &LogConfig) -> Result<()> {
+    open(config)?.install();
+    Ok(())
+}
+
+/// Log outputs that have been opened, but aren't used yet.
+pub struct Outputs(Vec<Output>);
+
+impl Outputs {
+    /// Starts logging to these outputs, replacing the current ones.
+    pub fn install(self) {
+        *LOGGER.outputs.write().unwrap() = self.0;
+    }
+}
+
+/// Opens the log outputs in `config`, without using them yet.
+pub fn open(config: &LogConfig) -> Result<Outputs> {
+    let json = config.format == "json";
+    let regular = if config.access_log_file.is_some() {
+        Filter::NotAccess
+    } else {
+        Filter::All
+    };
+
+    let mut outputs = vec![Output {
+        filter: regular,
+        logger: if json {
+            Box::new(JsonLogger::new(io::stdout()))
+        } else {
+            terminal_logger()
+        },
+    }];
+    for (path, filter) in [
+        (&config.file, regular),
+        (&config.access_log_file, Filter::Access),
+    ] {
+        if let Some(path) = path {
+            let file = RotatingFile::open(path, config.max_file_size, config.max_files)
+                .map_err(|err| anyhow!("Failed to open log file {}: {}", path, err))?;
+            outputs.push(Output {
+                filter,
+                logger: file_logger(file, json),
+            });
+        }
+    }
+
+    Ok(Outputs(outputs))
+}
+
+fn terminal_logger() -> Box<dyn Log> {
+    TermLogger::new(
+        LevelFilter::Trace,
+        simplelog::Config::default(),
+        TerminalMode::Mixed,
+        ColorChoice::Auto,
+    )
+}
+
+fn file_logger(file: RotatingFile, json: bool) -> Box<dyn Log> {
+    if json {
+        Box::new(JsonLogger::new(file))
+    } else {
+        WriteLogger::new(LevelFilter::Trace, simplelog::Config::default(), file)
+    }
+}
+
+/// Logs one JSON object per line, including any key-values of the record.
+struct JsonLogger<W> {
+    writer: Mutex<W>,
+}
+
+impl<W: Write> JsonLogger<W> {
+    fn new(writer: W) -> Self {
+        Self {
+            writer: Mutex::new(writer),
+        }
+    }
+}
+
+impl<W: Write + Send> Log for JsonLogger<W> {
+    fn enabled(&self, _: &Metadata) -> bool {
+        true
+    }
+
+    fn log(&self, record: &Record) {
+        let mut line = json_line(record);
+        line.push('\n');
+        // There's nowhere left to report this to
+        let _ = self.writer.lock().unwrap().write_all(line.as_bytes());
+    }
+
+    fn flush(&self) {
+        let _ = self.writer.lock().unwrap().flush();
+    }
+}
+
+fn json_line(record: &Record) -> String {
+    let mut fields = Map::new();
+    let time = OffsetDateTime::now_utc()
+        .format(&Rfc3339)
+        .unwrap_or_default();
+    fields.insert("time".to_string(), time.into());
+    fields.insert("level".to_string(), record.level().as_str().into());
+    fields.insert("target".to_string(), record.target().into());
+    fields.insert("message".to_string(), record.args().to_string().into());
+    let _ = record.key_values().visit(&mut JsonFields(&mut fields));
+    Value::Object(fields).to_string()
+}
+
+struct JsonFields<'a>(&'a mut Map<String, Value>);
+
+impl<'kvs> kv::VisitSource<'kvs> for JsonFields<'_> {
+    fn visit_pair(&mut self, key: kv::Key<'kvs>, value: kv::Value<'kvs>) -> Result<(), kv::Error> {
+        let mut json = JsonValue(Value::Null);
+        value.visit(&mut json)?;
+        self.0.insert(key.to_string(), json.0);
+        Ok(())
+    }
+}
+
+/// Keeps numbers and booleans as they are, and turns anything else into a string.
+struct JsonValue(Value);
+
+impl<'v> kv::VisitValue<'v> for JsonValue {
+    fn visit_any(&mut self, value: kv::Value) -> Result<(), kv::Error> {
+        self.0 = value.to_string().into();
+        Ok(())
+    }
+
+    fn visit_null(&mut self) -> Result<(), kv::Error> {
+        self.0 = Value::Null;
+        Ok(())
+    }
+
+    fn visit_u64(&mut self, value: u64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_i64(&mut self, value: i64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_f64(&mut self, value: f64) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+
+    fn visit_bool(&mut self, value: bool) -> Result<(), kv::Error> {
+        self.0 = value.into();
+        Ok(())
+    }
+}
+
+/// A log file that's moved to `<path>.1` once it gets too big, shifting older ones along.
+struct RotatingFile {
+    path: PathBuf,
+    file: File,
+    size: u64,
+    /// In bytes, 0 if the file is never rotated
+    max_size: u64,
+    max_files: usize,
+    /// Lines may be written in multiple parts, and shouldn't be split across files
+    at_line_start: bool,
+}
+
+impl RotatingFile {
+    fn open(path: impl AsRef<Path>, max_size_mb: u64, max_files: usize) -> io::Result<Self> {
+        let path = path.as_ref().to_path_buf();
+        let file = OpenOptions::new().create(true).append(true).open(&path)?;
+        let size = file.metadata()?.len();
+        Ok(Self {
+            path,
+            file,
+            size,
+            max_size: max_size_mb * MB_LEN as u64,
+            max_files,
+            at_line_start: true,
+        })
+    }
+
+    fn rotate(&mut self) -> io::Result<()> {
+        for i in (1..self.max_files).rev() {
+            match fs::rename(self.rotated_path(i), self.rotated_path(i + 1)) {
+                Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+                _ => {}
+            }
+        }
+        if self.max_files > 0 {
+            fs::rename(&self.path, self.rotated_path(1))?;
+        }
+        self.file = File::create(&self.path)?;
+        self.size = 0;
+        Ok(())
+    }
+
+    fn rotated_path(&self, i: usize) -> PathBuf {
+        let mut path = self.path.clone().into_os_string();
+        path.push(format!(".{}", i));
+        path.into()
+    }
+}
+
+impl Write for RotatingFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.at_line_start && self.max_size > 0 && self.size >= self.max_size {
+            self.rotate()?;
+        }
+        let written = self.file.write(buf)?;
+        self.size += written as u64;
+        if written > 0 {
+            self.at_line_start = buf[written - 1] == b'\n';
+        }
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rotating_file_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("bitbin.log");
+        let mut file = RotatingFile::open(&path, 0, 2).unwrap();
+        file.max_size = 10;
+
+        for line in [
+            "first line\n",
+            "second ",
+            "line\n",
+            "third line\n",
+            "fourth\n",
+        ] {
+            file.write_all(line.as_bytes()).unwrap();
+        }
+
+        let read = |i: usize| match i {
+            0 => fs::read_to_string(&path).unwrap(),
+            i => fs::read_to_string(file.rotated_path(i)).unwrap(),
+        };
+        assert_eq!(read(0), "fourth\n");
+        assert_eq!(read(1), "third line\n");
+        // Not split, even though it went over the limit halfway through
+        assert_eq!(read(2), "second line\n");
+        assert!(!file.rotated_path(3).exists());
+    }
+
+    #[test]
+    fn json_line_test() {
+        let kvs = [("status", kv::Value::from(404u64)), ("key", "abc".into())];
+        let record = Record::builder()
+            .args(format_args!("GET /abc"))
+            .level(log::Level::Info)
+            .target(ACCESS_TARGET)
+            .key_values(&kvs)
+            .build();
+
+        let line: Value = serde_json::from_str(&json_line(&record)).unwrap();
+        assert_eq!(line["level"], "INFO");
+        assert_eq!(line["target"], ACCESS_TARGET);
+        assert_eq!(line["message"], "GET /abc");
+        assert_eq!(line["status"], 404);
+        assert_eq!(line["key"], "abc");
+    }
+}
@@ -1,64 +1,117 @@
 #![forbid(unsafe_code)]
 
-use std::{
-    fs::{self, File},
-    io::BufReader,
-    path::PathBuf,
-    process::exit,
-    sync::Arc,
-    time::Duration,
-};
+use std::{path::PathBuf, process::ExitCode, sync::Arc, time::Duration};
 
 use actix_web::{
-    http::StatusCode,
+    http::{header, StatusCode},
     middleware,
-    web::{Data, PayloadConfig},
-    App, HttpServer,
+    web::Data,
+    App,
 };
 use anyhow::{bail, Result};
-use config::HttpConfig;
-use log::{debug, error, info, warn, LevelFilter};
-use r2d2::Pool;
-use r2d2_sqlite::SqliteConnectionManager;
-use rustls::{Certificate, PrivateKey, ServerConfig as RustlsServerConfig};
+use arc_swap::ArcSwap;
+use clap::Parser;
+use log::{debug, error, info, LevelFilter};
 
-use simplelog::{ColorChoice, CombinedLogger, TermLogger, TerminalMode};
 use storage::StorageBackend;
 
-use crate::{config::Config, storage::LocalStorage};
+use crate::{
+    cli::{Cli, Command},
+    config::Config,
+    crypto::Keyring,
+    fsck::Repair,
+    keys::KeyGenerator,
+    reload::Reloader,
+    stats::PendingStats,
+    storage::LocalStorage,
+    tasks::Tasks,
+    tls::CertResolver,
+};
 
+mod access_log;
+mod admin;
+mod auth;
+mod cli;
 mod config;
+mod content_type;
+mod crypto;
 mod data;
 mod db;
+mod decompress;
 mod errors;
+mod fsck;
 mod get;
+mod keys;
+mod logging;
+mod password;
 mod post;
+mod proxy;
+mod reload;
+mod server;
+mod stats;
 mod storage;
+mod tasks;
+mod telemetry;
+mod tls;
+mod view;
 
 const MB_LEN: usize = 1024 * 1024;
 
 pub struct State {
-    pool: Pool<SqliteConnectionManager>,
-    config: Config,
+    metadata: db::Metadata,
+    /// Swapped out when the config is reloaded
+    config: ArcSwap<Config>,
     storage: Arc<dyn StorageBackend + Sync + Send>,
+    /// Replaced when the key settings are reloaded
+    keys: ArcSwap<KeyGenerator>,
+    password_failures: password::Failures,
+    /// Written to the database periodically
+    stats: Arc<PendingStats>,
+}
+
+#[cfg(test)]
+impl State {
+    /// A state with its storage and database in `dir`, for testing handlers.
+    async fn for_tests(dir: &std::path::Path, mut config: Config) -> State {
+        config.storage.path = dir.join("content").to_string_lossy().to_string();
+        config.database.path = dir.join("bitbin.db").to_string_lossy().to_string();
+        let storage = LocalStorage::new(
+            PathBuf::from(&config.storage.path),
+            Keyring::from_config(&config.storage).unwrap(),
+            config.storage.verify_checksums,
+        );
+        storage.initialize().unwrap();
+        State {
+            metadata: db::open(&config.database).await.unwrap().0,
+            keys: ArcSwap::from_pointee(KeyGenerator::from_config(&config.misc).unwrap()),
+            config: ArcSwap::from_pointee(config),
+            storage: Arc::new(storage),
+            password_failures: password::Failures::default(),
+            stats: Arc::new(PendingStats::default()),
+        }
+    }
 }
 
 #[actix_web::main]
-async fn main() -> Result<()> {
+async fn main() -> ExitCode {
     if let Err(err) = start().await {
         error!("{:?}", err);
+        // So scripts can tell, e.g. when fsck finds problems
+        return ExitCode::FAILURE;
     }
-    Ok(())
+    ExitCode::SUCCESS
 }
 
 async fn start() -> Result<()> {
-    CombinedLogger::init(vec![TermLogger::new(
-        LevelFilter::Info,
-        simplelog::Config::default(),
-        TerminalMode::Mixed,
-        ColorChoice::Auto,
-    )])
-    .unwrap();
+    let cli = Cli::parse();
+
+    logging::init();
+    // Keep stdout clean so the config can be piped somewhere
+    log::set_max_level(if cli.print_config {
+        LevelFilter::Warn
+    } else {
+        LevelFilter::Info
+    });
 
     if let Ok(path) = dotenvy::dotenv() {
         info!(
@@ -67,16 +120,23 @@ async fn start() -> Result<()> {
         );
     }
 
-    let config = Config::create()?;
+    let config = Config::load(cli.config.as_deref(), cli.data_dir.as_deref())?;
 
-    info!(
-        "Starting bitbin v{}, listening on {}:{}!",
-        env!("CARGO_PKG_VERSION"),
-        config.http.host,
-        config.http.port
-    );
+    if cli.print_config {
+        print!("{}", config.to_redacted_toml()?);
+        return Ok(());
+    }
+    config.validate()?;
+    logging::configure(&config.log)?;
+    log::set_max_level(config.log_level());
+    telemetry::init(&config.telemetry)?;
 
-    let storage = LocalStorage::new(PathBuf::from("content"));
+    let keyring = Keyring::from_config(&config.storage)?;
+    let storage = LocalStorage::new(
+        PathBuf::from(&config.storage.path),
+        keyring,
+        config.storage.verify_checksums,
+    );
     if let Err(err) = storage.initialize() {
         bail!(
             "Failed to initialze {} storage: {}",
@@ -85,20 +145,13 @@ async fn start() -> Result<()> {
         );
     }
 
-    let db_dir = PathBuf::from("db");
-    if !db_dir.exists() {
-        fs::create_dir(&db_dir)?;
+    if let Some(Command::Reencrypt) = cli.command {
+        return reencrypt(&storage);
     }
 
-    let db_path = db_dir.join("bitbin.db");
-    let new_db = !db_path.exists();
-
-    let manager = SqliteConnectionManager::file(db_path);
-    let pool = Pool::new(manager).unwrap();
+    let (metadata, new_db) = db::open(&config.database).await?;
 
     if new_db {
-        let _ = db::create_db(pool.get()?);
-
         let all_content = match storage.list_all_content() {
             Ok(all_content) => all_content,
             Err(err) => {
@@ -108,7 +161,7 @@ async fn start() -> Result<()> {
         };
 
         for content in all_content {
-            match db::save_content_info(&pool, &content).await {
+            match db::save_content_info(&metadata, &content).await {
                 Ok(_) => debug!("Added existing paste {} to the database.", content.key),
                 Err(err) => error!(
                     "Failed to add existing paste {} to the database: {}",
@@ -118,101 +171,154 @@ async fn start() -> Result<()> {
         }
     }
 
+    if let Some(Command::Fsck { repair, delete }) = cli.command {
+        let repair = match (repair, delete) {
+            (false, _) => Repair::No,
+            (true, false) => Repair::Quarantine,
+            (true, true) => Repair::Delete,
+        };
+        return fsck::fsck(&metadata, &storage, repair).await;
+    }
+
+    info!("Starting bitbin v{}!", env!("CARGO_PKG_VERSION"));
+
+    let storage: Arc<dyn StorageBackend + Sync + Send> = Arc::new(storage);
+
+    let mut tasks = Tasks::default();
+
+    if config.content.expiry_sweep_interval > 0 {
+        let inactivity_expiry = Some(config.content.inactivity_expiry)
+            .filter(|&days| days > 0)
+            .map(|days| Duration::from_secs(days * 24 * 60 * 60));
+        tasks::spawn_expiry_sweep(
+            &mut tasks,
+            metadata.clone(),
+            storage.clone(),
+            Duration::from_secs(config.content.expiry_sweep_interval),
+            inactivity_expiry,
+        );
+    }
+
+    let stats = Arc::new(PendingStats::default());
+    if config.stats.enabled {
+        tasks::spawn_stats_flush(
+            &mut tasks,
+            metadata.clone(),
+            stats.clone(),
+            Duration::from_secs(config.stats.flush_interval),
+        );
+    }
+
+    if config.database.wal_checkpoint_interval > 0 {
+        tasks::spawn_checkpoint(
+            &mut tasks,
+            metadata.clone(),
+            Duration::from_secs(config.database.wal_checkpoint_interval),
+        );
+    }
+
     let data = Data::new(State {
-        pool,
-        config: config.clone(),
-        storage: Arc::new(storage),
+        metadata: metadata.clone(),
+        config: ArcSwap::from_pointee(config.clone()),
+        storage,
+        keys: ArcSwap::from_pointee(KeyGenerator::from_config(&config.misc)?),
+        password_failures: password::Failures::default(),
+        stats,
     });
 
-    let mut server = HttpServer::new(move || {
+    let certs = if config.http.uses_tls() {
+        Some(Arc::new(CertResolver::new(&config.http)?))
+    } else {
+        None
+    };
+
+    reload::spawn_reload_handler(
+        &mut tasks,
+        Reloader {
+            config_path: cli.config,
+            data_dir: cli.data_dir,
+            state: data.clone(),
+            certs: certs.clone(),
+        },
+    )?;
+
+    // To create a self-signed temporary cert for testing:
+    // openssl req -x509 -newkey rsa:4096 -nodes -keyout key.pem -out cert.pem -days 365 -subj '/CN=localhost'
+    let tls_config = match certs {
+        Some(certs) => Some(tls::build_tls_config(&config.http, certs)?),
+        None => None,
+    };
+
+    let server = server::build(&config.http, tls_config, move || {
         App::new()
             .app_data(data.clone())
-            .app_data(PayloadConfig::new(config.content.maxsize * MB_LEN))
+            // Browsers must not guess a different type than the one we send, e.g. for uploads
+            .wrap(
+                middleware::DefaultHeaders::new().add((header::X_CONTENT_TYPE_OPTIONS, "nosniff")),
+            )
             .wrap(
                 middleware::ErrorHandlers::new()
                     .handler(StatusCode::INTERNAL_SERVER_ERROR, errors::handle_500),
             )
+            .wrap(middleware::from_fn(telemetry::trace_request))
+            // Outermost, so it sees the final response and assigns the request ID first
+            .wrap(middleware::from_fn(access_log::log_request))
             // Routes
             .service(post::post)
+            .service(post::post_with_key)
+            .service(admin::total_stats)
+            .service(admin::content_stats)
+            .service(admin::list_content)
+            .service(admin::content_details)
+            .service(view::view)
+            .service(view::view_with_password)
             .service(get::get)
-    });
+    })?;
 
-    if config.http.keep_alive_timeout > 0.0 {
-        server = server.keep_alive(Duration::from_secs_f32(config.http.keep_alive_timeout));
-    } else {
-        server = server.keep_alive(None)
+    if let Err(err) = server.await {
+        error!("Server stopped unexpectedly: {}", err);
     }
 
-    if config.http.workers > 0 {
-        server = server.workers(config.http.workers);
+    info!("Shutting down...");
+    server::remove_sockets(&config.http);
+    tasks.shutdown().await;
+    telemetry::shutdown();
+    if let Err(err) = db::checkpoint(&metadata).await {
+        error!("Failed to checkpoint database: {}", err);
     }
-
-    let _ = if config.http.tls {
-        // To create a self-signed temporary cert for testing:
-        // openssl req -x509 -newkey rsa:4096 -nodes -keyout key.pem -out cert.pem -days 365 -subj '/CN=localhost'
-        server.bind_rustls_021(
-            (config.http.host.to_owned(), config.http.port),
-            build_tls_config(&config.http)?,
-        )
-    } else {
-        server.bind_auto_h2c((config.http.host, config.http.port))
-    }?
-    .run()
-    .await;
+    // Nothing else holds on to the database by now, so this closes it
+    drop(metadata);
+    info!("Stopped bitbin");
 
     Ok(())
 }
 
-fn build_tls_config(config: &HttpConfig) -> std::io::Result<RustlsServerConfig> {
-    Ok(RustlsServerConfig::builder()
-        .with_safe_defaults()
-        .with_no_client_auth()
-        .with_single_cert(create_cert_chain(config), PrivateKey(create_key(config)))
-        .unwrap())
-}
+/// Re-encrypts all content with the active key, so old keys can be removed.
+fn reencrypt(storage: &LocalStorage) -> Result<()> {
+    let keys = match storage.list_keys() {
+        Ok(keys) => keys,
+        Err(err) => bail!("Failed to list content: {}", err),
+    };
+    info!("Re-encrypting {} pastes...", keys.len());
 
-fn create_cert_chain(config: &HttpConfig) -> Vec<Certificate> {
-    let cert_file_path = config.tls_cert_file.as_ref().unwrap();
-    let cert_file = &mut BufReader::new(match File::open(cert_file_path) {
-        Ok(file) => file,
-        Err(err) => {
-            error!("Failed to load cert file '{}': {}", cert_file_path, err);
-            exit(1);
+    let (mut rewritten, mut failed) = (0, 0);
+    for key in keys {
+        match storage.reencrypt_content(&key) {
+            Ok(true) => rewritten += 1,
+            Ok(false) => {}
+            Err(err) => {
+                error!("Failed to re-encrypt paste {}: {}", key, err);
+                failed += 1;
+            }
         }
-    });
-
-    let cert_chain: Vec<Certificate> = rustls_pemfile::certs(cert_file)
-        .unwrap()
-        .into_iter()
-        .map(Certificate)
-        .collect();
-    if cert_chain.is_empty() {
-        error!("Failed to find any certs in '{}'", cert_file_path);
-        exit(1);
     }
-    cert_chain
-}
 
-fn create_key(config: &HttpConfig) -> Vec<u8> {
-    let key_file_path = config.tls_key_file.as_ref().unwrap();
-    let key_file = &mut BufReader::new(match File::open(key_file_path) {
-        Ok(file) => file,
-        Err(err) => {
-            error!("Failed to load key file '{}': {}", key_file_path, err);
-            exit(1);
-        }
-    });
-    let mut keys: Vec<Vec<u8>> = rustls_pemfile::pkcs8_private_keys(key_file).unwrap();
-    if keys.is_empty() {
-        error!("Failed to find any keys in '{}'", key_file_path);
-        exit(1);
-    }
-    if keys.len() > 1 {
-        warn!(
-            "Found multiple keys in '{}'! Only the first will be used.",
-            key_file_path
-        );
+    info!(
+        "Re-encrypted {} pastes, {} failed, the rest already used the active key.",
+        rewritten, failed
+    );
+    if failed > 0 {
+        bail!("Failed to re-encrypt {} pastes", failed);
     }
-
-    keys.remove(0)
+    Ok(())
 }
@@ -0,0 +1,238 @@
+use std::{
+    collections::HashMap,
+    net::IpAddr,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+use actix_web::{
+    error::{ErrorInternalServerError, ErrorTooManyRequests, InternalError},
+    http::header::{self, HeaderName},
+    web, Error, HttpRequest, HttpResponse,
+};
+use argon2::{
+    password_hash::{rand_core::OsRng, SaltString},
+    Argon2, PasswordHash, PasswordHasher, PasswordVerifier,
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+
+use crate::{config::ContentConfig, telemetry};
+
+/// Sets the password on upload, and can be used instead of Basic auth to provide it.
+pub const PASSWORD_HEADER: HeaderName = HeaderName::from_static("bitbin-password");
+
+/// Hashes a password for new content.
+pub async fn hash(password: String) -> Result<String, Error> {
+    telemetry::traced(
+        "argon2.hash",
+        web::block(move || {
+            let salt = SaltString::generate(&mut OsRng);
+            Argon2::default()
+                .hash_password(password.as_bytes(), &salt)
+                .map(|hash| hash.to_string())
+        }),
+    )
+    .await?
+    .map_err(ErrorInternalServerError)
+}
+
+/// Checks that the client sent the right password for the content with `key`.
+pub async fn check(
+    req: &HttpRequest,
+    failures: &Failures,
+    config: &ContentConfig,
+    key: &str,
+    hash: &str,
+) -> Result<(), Error> {
+    let Some(password) = from_request(req) else {
+        return Err(unauthorized("A password is required"));
+    };
+    if !verify(req, failures, config, key, hash, password).await? {
+        return Err(unauthorized("Wrong password"));
+    }
+    Ok(())
+}
+
+/// Checks `password` against `hash`, counting it as a failure of the client if it's wrong.
+/// Fails with 429 while the client is locked out of the content with `key`.
+pub async fn verify(
+    req: &HttpRequest,
+    failures: &Failures,
+    config: &ContentConfig,
+    key: &str,
+    hash: &str,
+    password: String,
+) -> Result<bool, Error> {
+    let lockout = Duration::from_secs(config.password_lockout);
+    // Per client, so nobody can lock everyone else out by guessing wrong on purpose
+    let client = req.peer_addr().map(|addr| addr.ip());
+    if failures.is_locked(client, key, config.password_max_failures, lockout) {
+        return Err(ErrorTooManyRequests(
+            "Too many wrong passwords, try again later",
+        ));
+    }
+
+    let hash = hash.to_string();
+    let valid = telemetry::traced(
+        "argon2.verify",
+        web::block(move || {
+            PasswordHash::new(&hash).is_ok_and(|hash| {
+                Argon2::default()
+                    .verify_password(password.as_bytes(), &hash)
+                    .is_ok()
+            })
+        }),
+    )
+    .await?;
+    if !valid {
+        failures.record(client, key, lockout);
+    }
+    Ok(valid)
+}
+
+/// The password from the Bitbin-Password header, which may be UTF-8.
+pub fn from_header(req: &HttpRequest) -> Option<String> {
+    let password = req.headers().get(PASSWORD_HEADER)?;
+    String::from_utf8(password.as_bytes().to_vec()).ok()
+}
+
+/// The password from the Bitbin-Password header, or the password of Basic auth.
+fn from_request(req: &HttpRequest) -> Option<String> {
+    if req.headers().contains_key(PASSWORD_HEADER) {
+        return from_header(req);
+    }
+    let credentials = req
+        .headers()
+        .get(header::AUTHORIZATION)?
+        .to_str()
+        .ok()?
+        .strip_prefix("Basic ")?;
+    let credentials = String::from_utf8(STANDARD.decode(credentials.trim()).ok()?).ok()?;
+    // The username doesn't matter
+    let (_, password) = credentials.split_once(':')?;
+    Some(password.to_string())
+}
+
+/// Lets browsers ask for the password with their own prompt.
+fn unauthorized(message: &'static str) -> Error {
+    let res = HttpResponse::Unauthorized()
+        .insert_header((
+            header::WWW_AUTHENTICATE,
+            "Basic realm=\"bitbin\", charset=\"UTF-8\"",
+        ))
+        .body(message);
+    InternalError::from_response(message, res).into()
+}
+
+/// Wrong passwords by client and key, so they can't be guessed quickly.
+/// Clients are told apart by IP, which comes from the PROXY header if there is one.
+/// Clients without one, e.g. on Unix sockets without PROXY headers, share a count.
+#[derive(Default)]
+pub struct Failures {
+    failures: Mutex<HashMap<(Option<IpAddr>, String), Window>>,
+}
+
+struct Window {
+    start: Instant,
+    count: u32,
+}
+
+impl Failures {
+    fn is_locked(
+        &self,
+        client: Option<IpAddr>,
+        key: &str,
+        max_failures: u32,
+        lockout: Duration,
+    ) -> bool {
+        self.failures
+            .lock()
+            .unwrap()
+            .get(&(client, key.to_string()))
+            .is_some_and(|window| window.start.elapsed() < lockout && window.count >= max_failures)
+    }
+
+    fn record(&self, client: Option<IpAddr>, key: &str, lockout: Duration) {
+        let mut failures = self.failures.lock().unwrap();
+        // Forget about old failures, so they don't pile up
+        failures.retain(|_, window| window.start.elapsed() < lockout);
+        failures
+            .entry((client, key.to_string()))
+            .or_insert_with(|| Window {
+                start: Instant::now(),
+                count: 0,
+            })
+            .count += 1;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test::TestRequest;
+
+    #[test]
+    fn from_request_test() {
+        let req = TestRequest::default()
+            .insert_header((PASSWORD_HEADER, "hunter2"))
+            .to_http_request();
+        assert_eq!(from_request(&req).as_deref(), Some("hunter2"));
+
+        let basic = format!("Basic {}", STANDARD.encode("anyone:hunter:2"));
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, basic))
+            .to_http_request();
+        assert_eq!(from_request(&req).as_deref(), Some("hunter:2"));
+
+        let req = TestRequest::default()
+            .insert_header((header::AUTHORIZATION, "Bearer hunter2"))
+            .to_http_request();
+        assert_eq!(from_request(&req), None);
+    }
+
+    #[test]
+    fn failures_test() {
+        let failures = Failures::default();
+        let lockout = Duration::from_secs(60);
+        let client = Some(IpAddr::from([192, 0, 2, 1]));
+        for _ in 0..3 {
+            assert!(!failures.is_locked(client, "abc", 3, lockout));
+            failures.record(client, "abc", lockout);
+        }
+        assert!(failures.is_locked(client, "abc", 3, lockout));
+        assert!(!failures.is_locked(client, "def", 3, lockout));
+        assert!(!failures.is_locked(None, "abc", 3, lockout));
+        assert!(!failures.is_locked(client, "abc", 3, Duration::ZERO));
+    }
+
+    #[actix_web::test]
+    async fn lockout_test() {
+        let failures = Failures::default();
+        let config = ContentConfig {
+            password_max_failures: 2,
+            ..Default::default()
+        };
+        let hash = hash("hunter2".to_string()).await.unwrap();
+        let req = |addr: &str, password: &str| {
+            TestRequest::default()
+                .peer_addr(addr.parse().unwrap())
+                .insert_header((PASSWORD_HEADER, password))
+                .to_http_request()
+        };
+        let check = |req| {
+            let (failures, config, hash) = (&failures, &config, &hash);
+            async move { check(&req, failures, config, "abc", hash).await }
+        };
+
+        let attacker = "192.0.2.1:1234";
+        for _ in 0..2 {
+            let err = check(req(attacker, "wrong")).await.unwrap_err();
+            assert_eq!(err.as_response_error().status_code(), 401);
+        }
+        let err = check(req(attacker, "hunter2")).await.unwrap_err();
+        assert_eq!(err.as_response_error().status_code(), 429);
+
+        // Someone else can still get in
+        assert!(check(req("198.51.100.7:4321", "hunter2")).await.is_ok());
+    }
+}
@@ -1,40 +1,100 @@
 use actix_web::{
-    error::{ErrorBadRequest, ErrorInternalServerError},
-    http::header::{self, ContentEncoding},
+    error::{
+        ErrorBadRequest, ErrorConflict, ErrorInternalServerError, ErrorPayloadTooLarge,
+        ErrorUnsupportedMediaType,
+    },
+    http::header::{self, ContentEncoding, HeaderName},
     post,
-    web::{self, Bytes, Data},
+    web::{self, Data, Path, Payload},
     Error, HttpMessage, HttpRequest, HttpResponse, Responder,
 };
 use anyhow::Result;
-use flate2::write::GzEncoder;
 use flate2::Compression;
-use serde::Serialize;
+use flate2::{read::GzDecoder, write::GzEncoder};
+use log::error;
+use serde::{Deserialize, Serialize};
 use std::{io::prelude::*, time::SystemTime};
 
 use crate::{
+    auth, content_type,
     db::{self, Content},
-    State,
+    decompress,
+    password::{self, PASSWORD_HEADER},
+    storage, telemetry, State, MB_LEN,
 };
 
+/// Lets authorized clients choose the key, like `POST /post/{key}`.
+pub const KEY_HEADER: HeaderName = HeaderName::from_static("bitbin-key");
+pub const MAX_VIEWS_HEADER: HeaderName = HeaderName::from_static("bitbin-max-views");
+/// The same as a max views of 1.
+pub const BURN_AFTER_READ_HEADER: HeaderName = HeaderName::from_static("bitbin-burn-after-read");
+/// Uploads of this type are read as an [`UploadForm`], like ones posted by an HTML form.
+const FORM_TYPE: &str = "application/x-www-form-urlencoded";
+
+/// The fields of a form upload. The content is saved as text, like an upload without a type.
+#[derive(Deserialize)]
+struct UploadForm {
+    content: String,
+    /// Browsers send empty fields, so an empty password is the same as none.
+    #[serde(default)]
+    password: String,
+}
+
 #[post("/post")]
 pub async fn post(
     state: Data<State>,
     req: HttpRequest,
-    bytes: Bytes,
+    payload: Payload,
 ) -> Result<impl Responder, Error> {
-    if bytes.is_empty() {
-        return Err(ErrorBadRequest("Missing content"));
-    }
+    let key = match req.headers().get(KEY_HEADER).map(|key| key.to_str()) {
+        Some(Ok(key)) => Some(key.to_string()),
+        Some(Err(_)) => return Err(ErrorBadRequest("Invalid key")),
+        None => None,
+    };
+    create(state, req, payload, key).await
+}
+
+#[post("/post/{key}")]
+pub async fn post_with_key(
+    state: Data<State>,
+    req: HttpRequest,
+    payload: Payload,
+    key: Path<String>,
+) -> Result<impl Responder, Error> {
+    create(state, req, payload, Some(key.into_inner())).await
+}
 
-    let content_type = Some(req.content_type())
-        .filter(|x| !x.is_empty())
-        .unwrap_or("text/plain")
-        .to_string();
+/// Saves the uploaded content under `key`, or a generated one if it isn't given.
+async fn create(
+    state: Data<State>,
+    req: HttpRequest,
+    payload: Payload,
+    key: Option<String>,
+) -> Result<HttpResponse, Error> {
+    let config = state.config.load();
+
+    // Checked before reading the content, so it isn't uploaded for nothing
+    if let Some(key) = &key {
+        auth::authorize(&req, &config.auth)?;
+        state
+            .keys
+            .load()
+            .check_vanity_key(key)
+            .map_err(ErrorBadRequest)?;
+    }
+    let max_views = max_views(&req)?;
+    let password = match password::from_header(&req) {
+        Some(password) if !password.is_empty() => Some(password),
+        _ if req.headers().contains_key(PASSWORD_HEADER) => {
+            return Err(ErrorBadRequest("Invalid password"))
+        }
+        _ => None,
+    };
 
-    let key = random_string::generate(
-        state.config.misc.keylength,
-        random_string::charsets::ALPHANUMERIC,
-    );
+    let form = req.content_type() == FORM_TYPE;
+    let declared_type = Some(req.content_type()).filter(|x| !x.is_empty() && !form);
+    let mut content_type =
+        content_type::check(&config.content, declared_type.unwrap_or("text/plain"))?;
 
     // ah sweet, man-made horros beyond my comprehension
     let mut content_encoding = req
@@ -55,20 +115,97 @@ pub async fn post(
                 .collect::<Vec<String>>()
         })
         .unwrap_or_default();
+    // Only gzip can be checked, and decompressed for clients that don't support it
+    content_encoding
+        .retain(|encoding| !encoding.is_empty() && encoding != ContentEncoding::Identity.as_str());
+    let gzipped = match content_encoding.as_slice() {
+        [] => false,
+        [encoding] if encoding == ContentEncoding::Gzip.as_str() => true,
+        _ => return Err(ErrorUnsupportedMediaType("Content-Encoding must be gzip")),
+    };
+    if form && gzipped {
+        return Err(ErrorUnsupportedMediaType("Form uploads can't be gzipped"));
+    }
 
-    let mut bytes: Vec<u8> = bytes.into();
+    // Limited here rather than with a PayloadConfig, so the limit can be reloaded
+    let limit = content_type::max_size(&config.content, &content_type) * MB_LEN;
+    let declared_len = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|h| h.to_str().ok())
+        .and_then(|len| len.parse::<usize>().ok());
+    if declared_len.is_some_and(|len| len > limit) {
+        return Err(ErrorPayloadTooLarge("Content too large"));
+    }
+    let bytes = match payload.to_bytes_limited(limit).await {
+        Ok(bytes) => bytes?,
+        Err(_) => return Err(ErrorPayloadTooLarge("Content too large")),
+    };
+    let (bytes, password) = if form {
+        let form: UploadForm =
+            serde_urlencoded::from_bytes(&bytes).map_err(|_| ErrorBadRequest("Invalid form"))?;
+        let form_password = Some(form.password).filter(|password| !password.is_empty());
+        if password.is_some() && form_password.is_some() {
+            return Err(ErrorBadRequest(
+                "Only one of Bitbin-Password and the password field can be set",
+            ));
+        }
+        (form.content.into(), password.or(form_password))
+    } else {
+        (bytes, password)
+    };
+    if bytes.is_empty() {
+        return Err(ErrorBadRequest("Missing content"));
+    }
+    if gzipped {
+        let limit = decompress::upload_limit(&config.content, bytes.len());
+        let compressed = bytes.clone();
+        let valid = telemetry::traced(
+            "gzip.validate",
+            web::block(move || decompress::validate(&compressed, limit)),
+        )
+        .await?;
+        match valid {
+            Ok(true) => {}
+            Ok(false) => return Err(ErrorPayloadTooLarge("Content too large when decompressed")),
+            Err(_) => return Err(ErrorBadRequest("Content isn't valid gzip")),
+        }
+    } else if bytes.len() as u64 > decompress::serving_limit(&config.content) {
+        // Otherwise it couldn't be decompressed for clients that don't support gzip
+        return Err(ErrorPayloadTooLarge("Content too large when decompressed"));
+    }
 
-    let compression_level = state.config.content.gzip_compression_level;
-    if content_encoding.is_empty() {
-        bytes = web::block(move || {
-            let mut gz = GzEncoder::new(Vec::new(), Compression::new(compression_level));
-            match gz.write_all(&bytes) {
-                Ok(_) => Ok(gz.finish()),
-                Err(err) => Err(err),
+    if config.content.sniff_types {
+        if let Some(sniffed) = sniff(&bytes, gzipped) {
+            // Checked like the declared type, so it can't be used to get around the lists
+            let sniffed = content_type::check(&config.content, sniffed)?;
+            if bytes.len() > content_type::max_size(&config.content, &sniffed) * MB_LEN {
+                return Err(ErrorPayloadTooLarge("Content too large"));
             }
-        })
+            if declared_type.is_none()
+                || content_type == content_type::FALLBACK_TYPE
+                || sniffed == content_type::FALLBACK_TYPE
+            {
+                content_type = sniffed;
+            }
+        }
+    }
+
+    let mut bytes: Vec<u8> = bytes.into();
+
+    let compression_level = config.content.gzip_compression_level;
+    if !gzipped {
+        bytes = telemetry::traced(
+            "gzip.compress",
+            web::block(move || {
+                let mut gz = GzEncoder::new(Vec::new(), Compression::new(compression_level));
+                match gz.write_all(&bytes) {
+                    Ok(_) => Ok(gz.finish()),
+                    Err(err) => Err(err),
+                }
+            }),
+        )
         .await???;
-        content_encoding = vec![ContentEncoding::Gzip.as_str().to_string()];
     }
 
     let last_modified: i64 = SystemTime::now()
@@ -78,35 +215,228 @@ pub async fn post(
         .try_into()
         .map_err(ErrorInternalServerError)?;
 
-    let content = Content {
-        key: key.clone(),
+    let password_hash = match password {
+        Some(password) => Some(password::hash(password).await?),
+        None => None,
+    };
+
+    let mut content = Content {
+        // Picked once it's saved
+        key: String::new(),
         content_type,
         expiry: None, // Not supported (yet)
         last_modified,
         modifiable: false, // Not supported (yet)
         auth_key: None,
-        content_encoding: content_encoding.join(","),
+        content_encoding: ContentEncoding::Gzip.as_str().to_string(),
         backend_id: state.storage.backend_id().to_string(),
         content_length: bytes.len(),
         content: Some(bytes),
+        max_views,
+        views: 0,
+        password_hash,
     };
 
-    if let Err(err) = db::save_content_info(&state.pool, &content).await {
-        return Err(ErrorInternalServerError(err));
-    };
-
-    if let Err(err) = state.storage.save_content(content) {
-        //db::delete_content_info();
-        return Err(ErrorInternalServerError(err));
+    match key {
+        Some(key) => {
+            content.key = key;
+            if !save_content(&state, &content).await? {
+                return Err(ErrorConflict("Key already exists"));
+            }
+        }
+        None => save_with_unused_key(&state, &mut content).await?,
     }
+    telemetry::record_key(&content.key);
 
-    let res = Response { key: &key };
+    let res = Response { key: &content.key };
     Ok(HttpResponse::Created()
         .insert_header(("Location", res.key))
         .json(res))
 }
 
+/// The view limit set with the Bitbin-Max-Views or Bitbin-Burn-After-Read headers, if any.
+fn max_views(req: &HttpRequest) -> Result<Option<u32>, Error> {
+    let header = |name| {
+        req.headers()
+            .get(name)
+            .map(|value| value.to_str().unwrap_or_default().trim())
+    };
+    match (header(MAX_VIEWS_HEADER), header(BURN_AFTER_READ_HEADER)) {
+        (None, None) => Ok(None),
+        (Some(max_views), None) => match max_views.parse() {
+            Ok(max_views) if max_views > 0 => Ok(Some(max_views)),
+            _ => Err(ErrorBadRequest(
+                "Bitbin-Max-Views must be a positive number",
+            )),
+        },
+        (None, Some("true")) => Ok(Some(1)),
+        (None, Some("false")) => Ok(None),
+        (None, Some(_)) => Err(ErrorBadRequest(
+            "Bitbin-Burn-After-Read must be true or false",
+        )),
+        (Some(_), Some(_)) => Err(ErrorBadRequest(
+            "Only one of Bitbin-Max-Views and Bitbin-Burn-After-Read can be set",
+        )),
+    }
+}
+
+/// The type of uploaded content from its first bytes.
+fn sniff(bytes: &[u8], gzipped: bool) -> Option<&'static str> {
+    if !gzipped {
+        return content_type::sniff(bytes);
+    }
+    let mut start = Vec::with_capacity(content_type::SNIFF_LEN);
+    GzDecoder::new(bytes)
+        .take(content_type::SNIFF_LEN as u64)
+        .read_to_end(&mut start)
+        .ok()?;
+    content_type::sniff(&start)
+}
+
+/// Saves `content` under the first key that isn't taken yet, setting `content.key` to it.
+async fn save_with_unused_key(state: &State, content: &mut Content) -> Result<(), Error> {
+    let keys = state.keys.load();
+    for (length, key) in keys.candidates() {
+        content.key = key;
+        if save_content(state, content).await? {
+            keys.grow_to(length);
+            return Ok(());
+        }
+    }
+    Err(ErrorInternalServerError("Failed to find an unused key"))
+}
+
+/// Saves `content` to the database and storage, returning false if its key is already used.
+async fn save_content(state: &State, content: &Content) -> Result<bool, Error> {
+    // Inserting into the database is atomic, so it decides who gets a key
+    match db::save_content_info(&state.metadata, content).await {
+        Ok(true) => {}
+        Ok(false) => return Ok(false),
+        Err(err) => return Err(ErrorInternalServerError(err)),
+    }
+
+    let saved = storage::save_content(&state.storage, content).await;
+    if !matches!(saved, Ok(true)) {
+        if let Err(err) = db::delete_content_info(&state.metadata, content.key.clone()).await {
+            error!("Failed to clean up database after failed save: {}", err);
+        }
+    }
+    // Storage can still have the key if it was left behind, e.g. by a crash
+    saved.map_err(ErrorInternalServerError)
+}
+
 #[derive(Serialize)]
 pub struct Response<'a> {
     key: &'a str,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, get};
+    use actix_web::{
+        http::StatusCode,
+        test::{self, TestRequest},
+        App,
+    };
+
+    #[test]
+    fn max_views_test() {
+        let views = |headers: &[(HeaderName, &str)]| {
+            let mut req = TestRequest::default();
+            for (name, value) in headers {
+                req = req.insert_header((name.clone(), *value));
+            }
+            max_views(&req.to_http_request()).ok()
+        };
+
+        assert_eq!(views(&[]), Some(None));
+        assert_eq!(views(&[(MAX_VIEWS_HEADER, "3")]), Some(Some(3)));
+        assert_eq!(views(&[(BURN_AFTER_READ_HEADER, "true")]), Some(Some(1)));
+        assert_eq!(views(&[(BURN_AFTER_READ_HEADER, "false")]), Some(None));
+        assert_eq!(views(&[(MAX_VIEWS_HEADER, "0")]), None);
+        assert_eq!(views(&[(MAX_VIEWS_HEADER, "-1")]), None);
+        assert_eq!(
+            views(&[(MAX_VIEWS_HEADER, "1"), (BURN_AFTER_READ_HEADER, "true")]),
+            None
+        );
+    }
+
+    #[actix_web::test]
+    async fn form_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Data::new(State::for_tests(dir.path(), Config::default()).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(post)
+                .service(get::get),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/post")
+            .insert_header((header::CONTENT_TYPE, FORM_TYPE))
+            .set_payload("content=hello+%3Cworld%3E&password=hunter2")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let key = res
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let stored = db::get_content_info(&state.metadata, key.clone())
+            .await
+            .unwrap()
+            .unwrap();
+        assert_eq!(stored.content_type, "text/plain");
+        assert!(stored.password_hash.is_some());
+
+        let req = TestRequest::get()
+            .uri(&format!("/{}", key))
+            .insert_header((PASSWORD_HEADER, "hunter2"))
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(test::read_body(res).await, "hello <world>");
+
+        // An empty field is what a browser sends when no password was typed in
+        let req = TestRequest::post()
+            .uri("/post")
+            .insert_header((header::CONTENT_TYPE, FORM_TYPE))
+            .set_payload("content=open&password=")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        assert_eq!(res.status(), StatusCode::CREATED);
+        let key = res
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap();
+        let stored = db::get_content_info(&state.metadata, key.to_string())
+            .await
+            .unwrap()
+            .unwrap();
+        assert!(stored.password_hash.is_none());
+
+        for (payload, password) in [
+            ("content=x&password=a", Some("b")),
+            ("password=a", None),
+            ("content=", None),
+        ] {
+            let mut req = TestRequest::post()
+                .uri("/post")
+                .insert_header((header::CONTENT_TYPE, FORM_TYPE))
+                .set_payload(payload);
+            if let Some(password) = password {
+                req = req.insert_header((PASSWORD_HEADER, password));
+            }
+            let res = test::call_service(&app, req.to_request()).await;
+            assert_eq!(res.status(), StatusCode::BAD_REQUEST, "{}", payload);
+        }
+    }
+}
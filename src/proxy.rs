@@ -0,0 +1,232 @@
+use std::{
+    io::{self, ErrorKind},
+    net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr},
+    pin::Pin,
+    str,
+    task::{Context, Poll},
+    time::Duration,
+};
+
+use actix_web::rt::{
+    net::{ActixStream, Ready},
+    time::timeout,
+};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, ReadBuf};
+
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+/// Including the CRLF, as defined by the spec.
+const V1_MAX_LENGTH: usize = 107;
+/// How long clients have to send the header, so idle connections don't pile up.
+const HEADER_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// A stream along with the address of the client, which may have come from a PROXY header.
+pub struct PeerStream<IO> {
+    io: IO,
+    pub peer_addr: Option<SocketAddr>,
+}
+
+impl<IO: AsyncRead + Unpin> PeerStream<IO> {
+    /// Wraps `io`, reading the PROXY header first if `proxy_protocol` is set.
+    /// `peer_addr` is used if the header doesn't have an address, e.g. for health checks.
+    pub async fn accept(
+        mut io: IO,
+        peer_addr: Option<SocketAddr>,
+        proxy_protocol: bool,
+    ) -> io::Result<Self> {
+        let peer_addr = if proxy_protocol {
+            match timeout(HEADER_TIMEOUT, read_header(&mut io)).await {
+                Ok(header) => header?.or(peer_addr),
+                Err(_) => return Err(invalid("Timed out waiting for the PROXY header")),
+            }
+        } else {
+            peer_addr
+        };
+        Ok(Self { io, peer_addr })
+    }
+}
+
+impl<IO> PeerStream<IO> {
+    pub fn get_ref(&self) -> &IO {
+        &self.io
+    }
+}
+
+impl<IO: AsyncRead + Unpin> AsyncRead for PeerStream<IO> {
+    fn poll_read(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &mut ReadBuf<'_>,
+    ) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_read(cx, buf)
+    }
+}
+
+impl<IO: AsyncWrite + Unpin> AsyncWrite for PeerStream<IO> {
+    fn poll_write(
+        mut self: Pin<&mut Self>,
+        cx: &mut Context<'_>,
+        buf: &[u8],
+    ) -> Poll<io::Result<usize>> {
+        Pin::new(&mut self.io).poll_write(cx, buf)
+    }
+
+    fn poll_flush(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_flush(cx)
+    }
+
+    fn poll_shutdown(mut self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+        Pin::new(&mut self.io).poll_shutdown(cx)
+    }
+}
+
+impl<IO: ActixStream> ActixStream for PeerStream<IO> {
+    fn poll_read_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<Ready>> {
+        IO::poll_read_ready(&self.io, cx)
+    }
+
+    fn poll_write_ready(&self, cx: &mut Context<'_>) -> Poll<io::Result<Ready>> {
+        IO::poll_write_ready(&self.io, cx)
+    }
+}
+
+/// Reads a PROXY protocol v1 or v2 header, returning the client's address if it has one.
+///
+/// Only the header is read, so the rest of the stream is left untouched.
+async fn read_header<IO: AsyncRead + Unpin>(io: &mut IO) -> io::Result<Option<SocketAddr>> {
+    // Both versions are at least this long
+    let mut start = [0; 12];
+    io.read_exact(&mut start).await?;
+
+    if &start == V2_SIGNATURE {
+        let mut header = [0; 4];
+        io.read_exact(&mut header).await?;
+        let mut addresses = vec![0; u16::from_be_bytes([header[2], header[3]]) as usize];
+        io.read_exact(&mut addresses).await?;
+        return parse_v2(header[0], header[1], &addresses);
+    }
+
+    if !start.starts_with(b"PROXY ") {
+        return Err(invalid("Missing PROXY header"));
+    }
+    // The line has to be read byte by byte, so none of the request is consumed
+    let mut line = start.to_vec();
+    while !line.ends_with(b"\r\n") {
+        if line.len() >= V1_MAX_LENGTH {
+            return Err(invalid("PROXY header is too long"));
+        }
+        line.push(io.read_u8().await?);
+    }
+    parse_v1(&line)
+}
+
+fn parse_v1(line: &[u8]) -> io::Result<Option<SocketAddr>> {
+    let line = str::from_utf8(line)
+        .map_err(|_| invalid("Invalid PROXY header"))?
+        .trim_end_matches("\r\n");
+    let parts: Vec<&str> = line.split(' ').collect();
+    match parts.as_slice() {
+        ["PROXY", "UNKNOWN", ..] => Ok(None),
+        ["PROXY", protocol @ ("TCP4" | "TCP6"), src, _dst, src_port, _dst_port] => {
+            let ip: IpAddr = src.parse().map_err(|_| invalid("Invalid PROXY address"))?;
+            if ip.is_ipv4() != (*protocol == "TCP4") {
+                return Err(invalid("PROXY address doesn't match the protocol"));
+            }
+            let port = src_port
+                .parse()
+                .map_err(|_| invalid("Invalid PROXY port"))?;
+            Ok(Some(SocketAddr::new(ip, port)))
+        }
+        _ => Err(invalid("Invalid PROXY header")),
+    }
+}
+
+fn parse_v2(version_command: u8, family: u8, addresses: &[u8]) -> io::Result<Option<SocketAddr>> {
+    if version_command >> 4 != 2 {
+        return Err(invalid("Unsupported PROXY version"));
+    }
+    match version_command & 0x0F {
+        // LOCAL, e.g. health checks from the proxy itself
+        0 => return Ok(None),
+        1 => {}
+        _ => return Err(invalid("Unsupported PROXY command")),
+    }
+
+    let (ip, port) = match family >> 4 {
+        1 if addresses.len() >= 12 => {
+            let src: [u8; 4] = addresses[0..4].try_into().unwrap();
+            (IpAddr::V4(Ipv4Addr::from(src)), &addresses[8..10])
+        }
+        2 if addresses.len() >= 36 => {
+            let src: [u8; 16] = addresses[0..16].try_into().unwrap();
+            (IpAddr::V6(Ipv6Addr::from(src)), &addresses[32..34])
+        }
+        1 | 2 => return Err(invalid("PROXY addresses are too short")),
+        // Unix sockets and unspecified addresses have nothing useful
+        _ => return Ok(None),
+    };
+    Ok(Some(SocketAddr::new(
+        ip,
+        u16::from_be_bytes([port[0], port[1]]),
+    )))
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(ErrorKind::InvalidData, message)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn read(data: &[u8]) -> (io::Result<Option<SocketAddr>>, Vec<u8>) {
+        let mut io = data;
+        let addr = read_header(&mut io).await;
+        let mut rest = Vec::new();
+        io.read_to_end(&mut rest).await.unwrap();
+        (addr, rest)
+    }
+
+    #[actix_web::test]
+    async fn v1_test() {
+        let (addr, rest) = read(b"PROXY TCP4 192.0.2.1 198.51.100.1 56324 443\r\nGET /").await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let (addr, _) = read(b"PROXY TCP6 2001:db8::1 2001:db8::2 56324 443\r\n").await;
+        assert_eq!(addr.unwrap(), Some("[2001:db8::1]:56324".parse().unwrap()));
+
+        let (addr, _) = read(b"PROXY UNKNOWN\r\n").await;
+        assert_eq!(addr.unwrap(), None);
+
+        assert!(read(b"PROXY TCP4 2001:db8::1 2001:db8::2 1 2\r\n")
+            .await
+            .0
+            .is_err());
+        assert!(read(b"GET / HTTP/1.1\r\nHost: x\r\n").await.0.is_err());
+        assert!(read(&[b"PROXY ".as_slice(), &[b'a'; 200]].concat())
+            .await
+            .0
+            .is_err());
+    }
+
+    #[actix_web::test]
+    async fn v2_test() {
+        let mut header = V2_SIGNATURE.to_vec();
+        header.extend([0x21, 0x11, 0, 12]);
+        header.extend([192, 0, 2, 1, 198, 51, 100, 1]);
+        header.extend(56324u16.to_be_bytes());
+        header.extend(443u16.to_be_bytes());
+        header.extend(b"GET /");
+        let (addr, rest) = read(&header).await;
+        assert_eq!(addr.unwrap(), Some("192.0.2.1:56324".parse().unwrap()));
+        assert_eq!(rest, b"GET /");
+
+        let mut local = V2_SIGNATURE.to_vec();
+        local.extend([0x20, 0x00, 0, 0]);
+        assert_eq!(read(&local).await.0.unwrap(), None);
+
+        let mut short = V2_SIGNATURE.to_vec();
+        short.extend([0x21, 0x11, 0, 4, 192, 0, 2, 1]);
+        assert!(read(&short).await.0.is_err());
+    }
+}
@@ -0,0 +1,200 @@
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+
+use actix_web::{
+    rt::signal::unix,
+    web::{self, Data},
+};
+use anyhow::Result;
+use log::{error, info, warn};
+
+use crate::{
+    config::Config,
+    keys::{self, KeyGenerator},
+    logging,
+    tasks::Tasks,
+    tls::{CertResolver, Certs},
+    State,
+};
+
+/// Re-reads the config and TLS certs, applying whatever can be changed while running.
+pub struct Reloader {
+    pub config_path: Option<PathBuf>,
+    pub data_dir: Option<PathBuf>,
+    pub state: Data<State>,
+    pub certs: Option<Arc<CertResolver>>,
+}
+
+/// Everything a reload needs that can fail, so nothing is swapped until all of it has worked.
+struct Prepared {
+    config: Config,
+    keys: Option<KeyGenerator>,
+    /// Whether the new keys should stay as long as collisions made the current ones
+    keep_key_length: bool,
+    certs: Option<Certs>,
+    log_outputs: logging::Outputs,
+}
+
+impl Reloader {
+    /// Reloads everything, keeping the current config and certs if anything fails.
+    pub async fn reload(&self) -> Result<()> {
+        let config_path = self.config_path.clone();
+        let data_dir = self.data_dir.clone();
+        let current = self.state.config.load_full();
+        let load_certs = self.certs.is_some();
+        // Reading the config, certs, word list and log files all block
+        let prepared = web::block(move || {
+            prepare(
+                config_path.as_deref(),
+                data_dir.as_deref(),
+                &current,
+                load_certs,
+            )
+        })
+        .await??;
+
+        if let (Some(resolver), Some(certs)) = (&self.certs, prepared.certs) {
+            resolver.swap(certs);
+        }
+        prepared.log_outputs.install();
+        log::set_max_level(prepared.config.log_level());
+        if let Some(keys) = prepared.keys {
+            if prepared.keep_key_length {
+                keys.grow_to(self.state.keys.load().length());
+            }
+            self.state.keys.store(Arc::new(keys));
+        }
+        self.state.config.store(Arc::new(prepared.config));
+        Ok(())
+    }
+}
+
+fn prepare(
+    config_path: Option<&Path>,
+    data_dir: Option<&Path>,
+    current: &Config,
+    load_certs: bool,
+) -> Result<Prepared> {
+    let new = Config::load(config_path, data_dir)?;
+    new.validate()?;
+
+    let mut config = current.clone();
+    let keys = if new.misc != config.misc {
+        Some(KeyGenerator::from_config(&new.misc)?)
+    } else {
+        None
+    };
+    let keep_key_length = keys::same_keyspace(&config.misc, &new.misc);
+    let certs = if load_certs {
+        let certs = CertResolver::load(&new.http)?;
+        config.http.tls_key_file = new.http.tls_key_file.clone();
+        config.http.tls_cert_file = new.http.tls_cert_file.clone();
+        config.http.tls_sni = new.http.tls_sni.clone();
+        Some(certs)
+    } else {
+        None
+    };
+    let log_outputs = logging::open(&new.log)?;
+    config.apply_runtime_settings(&new);
+
+    if config != new {
+        warn!("Some of the changed settings will only take effect after a restart");
+    }
+    Ok(Prepared {
+        config,
+        keys,
+        keep_key_length,
+        certs,
+        log_outputs,
+    })
+}
+
+/// Reloads on every SIGHUP.
+pub fn spawn_reload_handler(tasks: &mut Tasks, reloader: Reloader) -> Result<()> {
+    let mut hangup = unix::signal(unix::SignalKind::hangup())?;
+    tasks.spawn(|mut shutdown| async move {
+        loop {
+            tokio::select! {
+                signal = hangup.recv() => if signal.is_none() { break },
+                _ = shutdown.changed() => break,
+            }
+            info!("Received SIGHUP, reloading config...");
+            match reloader.reload().await {
+                Ok(_) => info!("Reloaded config"),
+                Err(err) => error!("Failed to reload config, keeping the old one: {}", err),
+            }
+        }
+    });
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    async fn reloader(dir: &Path) -> Reloader {
+        let state = State::for_tests(dir, Config::default()).await;
+        Reloader {
+            config_path: Some(dir.join("config.toml")),
+            data_dir: None,
+            state: Data::new(state),
+            certs: None,
+        }
+    }
+
+    #[actix_web::test]
+    async fn invalid_config_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let reloader = reloader(dir.path()).await;
+        let config = reloader.state.config.load_full();
+        let keys = reloader.state.keys.load_full();
+
+        for invalid in ["[misc]\nkeylength = 0\n", "[misc\nkeylength = 12\n"] {
+            fs::write(dir.path().join("config.toml"), invalid).unwrap();
+            assert!(reloader.reload().await.is_err());
+            assert!(Arc::ptr_eq(&reloader.state.config.load_full(), &config));
+            assert!(Arc::ptr_eq(&reloader.state.keys.load_full(), &keys));
+        }
+    }
+
+    #[actix_web::test]
+    async fn reload_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let reloader = reloader(dir.path()).await;
+        let old = reloader.state.config.load_full();
+        let keys = reloader.state.keys.load_full();
+
+        let changed = "[http]\nport = 9999\n[misc]\nkeylength = 12\n[content]\nmaxsize = 5\n";
+        fs::write(dir.path().join("config.toml"), changed).unwrap();
+        reloader.reload().await.unwrap();
+
+        let new = reloader.state.config.load();
+        assert_eq!(new.misc.keylength, 12);
+        assert_eq!(new.content.maxsize, 5);
+        assert!(!Arc::ptr_eq(&reloader.state.keys.load_full(), &keys));
+        // These need a restart
+        assert_eq!(new.http.port, old.http.port);
+        assert_eq!(new.storage.path, old.storage.path);
+        assert_eq!(new.database, old.database);
+    }
+
+    #[actix_web::test]
+    async fn key_length_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let reloader = reloader(dir.path()).await;
+        reloader.state.keys.load().grow_to(20);
+
+        fs::write(dir.path().join("config.toml"), "[misc]\nkeylength = 12\n").unwrap();
+        reloader.reload().await.unwrap();
+        assert_eq!(reloader.state.keys.load().length(), 20);
+
+        // Lengths from another keyspace don't mean anything for the new one
+        let changed = "[misc]\nkeylength = 12\nkey_strategy = \"pronounceable\"\n";
+        fs::write(dir.path().join("config.toml"), changed).unwrap();
+        reloader.reload().await.unwrap();
+        assert_eq!(reloader.state.keys.load().length(), 12);
+    }
+}
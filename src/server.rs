@@ -0,0 +1,319 @@
+use std::{
+    fmt, fs, io, net,
+    os::unix::{
+        fs::{chown, FileTypeExt, PermissionsExt},
+        net::UnixListener,
+    },
+    time::Duration,
+};
+
+use actix_http::{
+    body::MessageBody, error::DispatchError, HttpService, KeepAlive, Protocol, Request, Response,
+};
+use actix_server::{GracefulShutdownSignal, Server, ServerBuilder};
+use actix_service::{
+    fn_service, map_config, IntoServiceFactory, Service, ServiceFactory, ServiceFactoryExt,
+};
+use actix_tls::accept::{
+    rustls_0_21::{Acceptor, TlsStream},
+    TlsError,
+};
+use actix_web::{
+    dev::{AppConfig, Extensions},
+    rt::net::{ActixStream, TcpStream, UnixStream},
+    Error,
+};
+use anyhow::{anyhow, bail, Result};
+use log::info;
+use nix::unistd::{Gid, Group, Uid, User};
+use rustls::ServerConfig as RustlsServerConfig;
+use tokio::io::{AsyncRead, AsyncWrite};
+
+use crate::{
+    config::{HttpConfig, ListenerConfig},
+    proxy::PeerStream,
+    tls,
+};
+
+/// Settings shared by the HTTP service of every listener.
+#[derive(Clone)]
+struct Options {
+    keep_alive: KeepAlive,
+    shutdown: GracefulShutdownSignal,
+}
+
+/// Builds a server listening on all configured listeners.
+///
+/// `tls_config` is required if any of them use TLS.
+pub fn build<F, I, S, B>(
+    config: &HttpConfig,
+    tls_config: Option<RustlsServerConfig>,
+    factory: F,
+) -> Result<Server>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let mut builder = actix_server::Server::build()
+        // Connections are given this long to finish after SIGTERM or SIGINT
+        .shutdown_timeout(config.shutdown_timeout);
+    if config.workers > 0 {
+        builder = builder.workers(config.workers);
+    }
+
+    let options = Options {
+        keep_alive: if config.keep_alive_timeout > 0.0 {
+            KeepAlive::Timeout(Duration::from_secs_f32(config.keep_alive_timeout))
+        } else {
+            KeepAlive::Disabled
+        },
+        shutdown: builder.graceful_shutdown_signal(),
+    };
+
+    for listener in config.listeners() {
+        let tls_config = if listener.tls {
+            match &tls_config {
+                Some(tls_config) => Some(tls_config.clone()),
+                None => bail!("{} uses TLS, but TLS isn't set up", listener.address),
+            }
+        } else {
+            None
+        };
+        builder = listen(builder, &listener, tls_config, &options, &factory)?;
+        info!(
+            "Listening on {}{}{}",
+            listener.address,
+            if listener.tls { " with TLS" } else { "" },
+            if listener.proxy_protocol {
+                " behind a proxy"
+            } else {
+                ""
+            }
+        );
+    }
+
+    Ok(builder.run())
+}
+
+/// Removes the socket files of Unix listeners.
+pub fn remove_sockets(config: &HttpConfig) {
+    for listener in config.listeners() {
+        if let Some(path) = listener.unix_path() {
+            let _ = fs::remove_file(path);
+        }
+    }
+}
+
+fn listen<F, I, S, B>(
+    builder: ServerBuilder,
+    listener: &ListenerConfig,
+    tls_config: Option<RustlsServerConfig>,
+    options: &Options,
+    factory: &F,
+) -> Result<ServerBuilder>
+where
+    F: Fn() -> I + Send + Clone + 'static,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let name = format!("bitbin-{}", listener.address);
+    let (options, factory) = (options.clone(), factory.clone());
+
+    let proxy_protocol = listener.proxy_protocol;
+
+    let builder = match (listener.unix_path(), tls_config) {
+        (None, None) => builder.listen(name, bind_tcp(listener)?, move || {
+            fn_service(move |io: TcpStream| async move {
+                let peer_addr = io.peer_addr().ok();
+                let io = PeerStream::accept(io, peer_addr, proxy_protocol).await?;
+                let protocol = detect_h2c(io.get_ref()).await?;
+                let peer_addr = io.peer_addr;
+                Ok((io, protocol, peer_addr))
+            })
+            .and_then(http_service(&factory, &options, None))
+        }),
+        (None, Some(tls_config)) => builder.listen(name, bind_tcp(listener)?, move || {
+            fn_service(move |io: TcpStream| async move {
+                let peer_addr = io.peer_addr().ok();
+                PeerStream::accept(io, peer_addr, proxy_protocol)
+                    .await
+                    .map_err(TlsError::Tls)
+            })
+            .and_then(tls_acceptor(tls_config.clone()))
+            .map(with_protocol)
+            .and_then(
+                http_service(&factory, &options, Some(tls::on_connect)).map_err(TlsError::Service),
+            )
+        }),
+        (Some(path), None) => builder.listen_uds(name, bind_unix(path, listener)?, move || {
+            fn_service(move |io: UnixStream| async move {
+                let io = PeerStream::accept(io, None, proxy_protocol).await?;
+                let peer_addr = io.peer_addr;
+                Ok((io, Protocol::Http1, peer_addr))
+            })
+            .and_then(http_service(&factory, &options, None))
+        }),
+        (Some(path), Some(tls_config)) => {
+            builder.listen_uds(name, bind_unix(path, listener)?, move || {
+                fn_service(move |io: UnixStream| async move {
+                    PeerStream::accept(io, None, proxy_protocol)
+                        .await
+                        .map_err(TlsError::Tls)
+                })
+                .and_then(tls_acceptor(tls_config.clone()))
+                .map(with_protocol)
+                .and_then(
+                    http_service(&factory, &options, Some(tls::on_connect))
+                        .map_err(TlsError::Service),
+                )
+            })
+        }
+    }?;
+    Ok(builder)
+}
+
+/// The HTTP service connections are handed to once they're set up.
+fn http_service<T, F, I, S, B>(
+    factory: &F,
+    options: &Options,
+    on_connect: Option<fn(&T, &mut Extensions)>,
+) -> impl ServiceFactory<
+    (T, Protocol, Option<net::SocketAddr>),
+    Config = (),
+    Response = (),
+    Error = DispatchError,
+    InitError = (),
+>
+where
+    T: AsyncRead + AsyncWrite + Unpin + 'static,
+    F: Fn() -> I,
+    I: IntoServiceFactory<S, Request>,
+    S: ServiceFactory<Request, Config = AppConfig> + 'static,
+    S::Error: Into<Error> + 'static,
+    S::InitError: fmt::Debug,
+    S::Response: Into<Response<B>> + 'static,
+    <S::Service as Service<Request>>::Future: 'static,
+    S::Service: 'static,
+    B: MessageBody + 'static,
+{
+    let shutdown = options.shutdown.clone();
+    let mut service = HttpService::build()
+        .keep_alive(options.keep_alive)
+        // Lets idle keep-alive connections close right away on shutdown
+        .graceful_shutdown_signal(move || {
+            let shutdown = shutdown.clone();
+            async move { shutdown.notified().await }
+        });
+    if let Some(on_connect) = on_connect {
+        service = service.on_connect_ext(on_connect);
+    }
+
+    let app = factory()
+        .into_factory()
+        .map_err(|err| err.into().error_response());
+    service.finish(map_config(app, |_| AppConfig::default()))
+}
+
+fn tls_acceptor<IO>(
+    tls_config: RustlsServerConfig,
+) -> impl ServiceFactory<
+    IO,
+    Config = (),
+    Response = TlsStream<IO>,
+    Error = TlsError<io::Error, DispatchError>,
+    InitError = (),
+>
+where
+    IO: ActixStream + 'static,
+{
+    Acceptor::new(tls_config)
+        .map_init_err(|_| ())
+        .map_err(TlsError::into_service_error)
+}
+
+/// Picks the protocol negotiated through ALPN.
+fn with_protocol<IO>(
+    io: TlsStream<PeerStream<IO>>,
+) -> (TlsStream<PeerStream<IO>>, Protocol, Option<net::SocketAddr>) {
+    let (stream, session) = io.get_ref();
+    let protocol = if session.alpn_protocol() == Some(b"h2") {
+        Protocol::Http2
+    } else {
+        Protocol::Http1
+    };
+    let peer_addr = stream.peer_addr;
+    (io, protocol, peer_addr)
+}
+
+/// Checks whether a plain connection starts with the HTTP/2 preface, like actix-web does.
+async fn detect_h2c(io: &TcpStream) -> Result<Protocol, DispatchError> {
+    const H2_PREFACE: &[u8] = b"PRI * HTTP/2";
+    let mut buf = [0; 12];
+    io.peek(&mut buf).await?;
+    Ok(if buf == H2_PREFACE {
+        Protocol::Http2
+    } else {
+        Protocol::Http1
+    })
+}
+
+fn bind_tcp(listener: &ListenerConfig) -> Result<net::TcpListener> {
+    net::TcpListener::bind(&listener.address)
+        .map_err(|err| anyhow!("Failed to listen on {}: {}", listener.address, err))
+}
+
+fn bind_unix(path: &str, listener: &ListenerConfig) -> Result<UnixListener> {
+    // Left behind if bitbin didn't shut down cleanly
+    if fs::symlink_metadata(path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        fs::remove_file(path)?;
+    }
+    let socket = UnixListener::bind(path)
+        .map_err(|err| anyhow!("Failed to listen on {}: {}", listener.address, err))?;
+
+    if let Some(mode) = &listener.mode {
+        let mode = u32::from_str_radix(mode, 8)?;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))?;
+    }
+
+    let owner = listener.owner.as_deref().map(find_user).transpose()?;
+    let group = listener.group.as_deref().map(find_group).transpose()?;
+    if owner.is_some() || group.is_some() {
+        chown(path, owner.map(Uid::as_raw), group.map(Gid::as_raw))
+            .map_err(|err| anyhow!("Failed to change the owner of {}: {}", path, err))?;
+    }
+
+    Ok(socket)
+}
+
+fn find_user(name: &str) -> Result<Uid> {
+    if let Ok(id) = name.parse() {
+        return Ok(Uid::from_raw(id));
+    }
+    match User::from_name(name)? {
+        Some(user) => Ok(user.uid),
+        None => bail!("Unknown user '{}'", name),
+    }
+}
+
+fn find_group(name: &str) -> Result<Gid> {
+    if let Ok(id) = name.parse() {
+        return Ok(Gid::from_raw(id));
+    }
+    match Group::from_name(name)? {
+        Some(group) => Ok(group.gid),
+        None => bail!("Unknown group '{}'", name),
+    }
+}
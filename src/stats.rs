@@ -0,0 +1,87 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use anyhow::Result;
+
+use crate::db::{self, ContentStats, Metadata};
+
+/// Stats that haven't been written to the database yet, so views don't have to wait for it.
+#[derive(Default)]
+pub struct PendingStats {
+    stats: Mutex<HashMap<String, ContentStats>>,
+}
+
+impl PendingStats {
+    pub fn record_view(&self, key: &str, bytes_served: u64, now: i64) {
+        let view = ContentStats {
+            views: 1,
+            bytes_served,
+            last_accessed: Some(now),
+        };
+        self.stats
+            .lock()
+            .unwrap()
+            .entry(key.to_string())
+            .or_default()
+            .add(&view);
+    }
+
+    /// The stats of `key` that haven't been written yet.
+    pub fn get(&self, key: &str) -> Option<ContentStats> {
+        self.stats.lock().unwrap().get(key).copied()
+    }
+
+    /// The stats of all content that haven't been written yet.
+    pub fn total(&self) -> ContentStats {
+        let mut total = ContentStats::default();
+        for stats in self.stats.lock().unwrap().values() {
+            total.add(stats);
+        }
+        total
+    }
+
+    /// Writes everything to the database, keeping it around for the next try if that fails.
+    pub async fn flush(&self, metadata: &Metadata) -> Result<()> {
+        let batch: Vec<_> = self.stats.lock().unwrap().drain().collect();
+        if batch.is_empty() {
+            return Ok(());
+        }
+        if let Err(err) = db::add_stats(metadata, batch.clone()).await {
+            self.restore(batch);
+            return Err(err);
+        }
+        Ok(())
+    }
+
+    fn restore(&self, batch: Vec<(String, ContentStats)>) {
+        let mut stats = self.stats.lock().unwrap();
+        for (key, batch) in batch {
+            stats.entry(key).or_default().add(&batch);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn pending_stats_test() {
+        let pending = PendingStats::default();
+        pending.record_view("abc", 100, 2000);
+        pending.record_view("abc", 50, 1000);
+        pending.record_view("def", 10, 3000);
+
+        let abc = pending.get("abc").unwrap();
+        assert_eq!(abc.views, 2);
+        assert_eq!(abc.bytes_served, 150);
+        assert_eq!(abc.last_accessed, Some(2000));
+
+        let batch: Vec<_> = pending.stats.lock().unwrap().drain().collect();
+        pending.record_view("abc", 1, 4000);
+        pending.restore(batch);
+        let total = pending.total();
+        assert_eq!(total.views, 4);
+        assert_eq!(total.bytes_served, 161);
+        assert_eq!(total.last_accessed, Some(4000));
+    }
+}
@@ -1,56 +1,179 @@
-use std::{fs, path::PathBuf};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
 
 use actix_web::{
-    error::{ErrorInternalServerError, ErrorNotFound},
+    error::{ErrorInternalServerError, ErrorNotFound, InternalError},
     http::header::ContentEncoding,
-    Result,
+    web, Error, Result,
 };
+use bytes::Bytes;
 use log::error;
 
 use crate::{
+    crypto::{self, Keyring},
     data::{DataReader, DataWriter},
     db::Content,
+    telemetry,
 };
 
 pub trait StorageBackend {
     fn backend_id(&self) -> &'static str;
     fn initialize(&self) -> Result<()>;
-    fn save_content(&self, content: Content) -> Result<()>;
+    /// Returns false without saving anything if the key is already used.
+    fn save_content(&self, content: &Content) -> Result<bool>;
     fn get_content(&self, key: &str, skip_content: bool) -> Result<Content>;
+    fn delete_content(&self, key: &str) -> Result<()>;
+    fn list_keys(&self) -> Result<Vec<String>>;
     fn list_all_content(&self) -> Result<Vec<Content>>;
 }
 
+pub async fn save_content(
+    storage: &Arc<dyn StorageBackend + Sync + Send>,
+    content: &Content,
+) -> Result<bool> {
+    let storage = storage.clone();
+    let content = content.clone();
+    blocking("storage.save_content", content.key.clone(), move || {
+        storage.save_content(&content)
+    })
+    .await
+}
+
+pub async fn get_content(
+    storage: &Arc<dyn StorageBackend + Sync + Send>,
+    key: String,
+    skip_content: bool,
+) -> Result<Content> {
+    let storage = storage.clone();
+    blocking("storage.get_content", key.clone(), move || {
+        storage.get_content(&key, skip_content)
+    })
+    .await
+}
+
+pub async fn delete_content(
+    storage: &Arc<dyn StorageBackend + Sync + Send>,
+    key: String,
+) -> Result<()> {
+    let storage = storage.clone();
+    blocking("storage.delete_content", key.clone(), move || {
+        storage.delete_content(&key)
+    })
+    .await
+}
+
+/// Runs `f` on the blocking thread pool, in a span with the key of the content it works on.
+async fn blocking<T: Send + 'static>(
+    name: &'static str,
+    key: String,
+    f: impl FnOnce() -> Result<T> + Send + 'static,
+) -> Result<T> {
+    let result = web::block(telemetry::in_current_context(move || {
+        // Errors aren't Send, so only their status and message make it out of the thread pool
+        telemetry::traced_sync_key(name, &key, f)
+            .map_err(|err| (err.as_response_error().status_code(), err.to_string()))
+    }))
+    .await?;
+    result.map_err(|(status, message)| InternalError::new(message, status).into())
+}
+
+/// Where [`LocalStorage::quarantine_content`] moves content to, relative to the storage path.
+const QUARANTINE_DIR: &str = ".quarantine";
+/// The storage format version [`LocalStorage`] writes. It reads all versions up to this one.
+const FORMAT_VERSION: i32 = 3;
+/// Content is written to `.{key}.tmp` first, see [`LocalStorage::write_atomically`].
+const TMP_SUFFIX: &str = ".tmp";
+
+/// Why content couldn't be read.
+#[derive(Debug)]
+pub enum ReadError {
+    /// The file is damaged, e.g. it's truncated, fails its checksum or can't be decrypted.
+    Corrupt(Error),
+    /// The file couldn't be read or needs a key we don't have, so it may well be fine.
+    Unavailable(Error),
+}
+
+impl From<ReadError> for Error {
+    fn from(err: ReadError) -> Self {
+        match err {
+            ReadError::Corrupt(err) | ReadError::Unavailable(err) => err,
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct LocalStorage {
     pub path: PathBuf,
+    keyring: Keyring,
+    verify_checksums: bool,
 }
 
 impl LocalStorage {
-    pub fn new(path: PathBuf) -> Self {
-        Self { path }
+    pub fn new(path: PathBuf, keyring: Keyring, verify_checksums: bool) -> Self {
+        Self {
+            path,
+            keyring,
+            verify_checksums,
+        }
     }
-}
 
-impl StorageBackend for LocalStorage {
-    fn backend_id(&self) -> &'static str {
-        "local"
+    /// Rewrites the content with the active encryption key.
+    /// Returns false if it was already using it.
+    pub fn reencrypt_content(&self, key: &str) -> Result<bool> {
+        let data_path = self.path.join(key);
+        let (content, key_id) = self.read_content(key, false)?;
+        if key_id == self.keyring.active_key_id() {
+            return Ok(false);
+        }
+
+        let data = self.encode_content(&content)?;
+        self.write_atomically(key, &data_path, &data)?;
+
+        Ok(true)
     }
 
-    fn initialize(&self) -> Result<()> {
-        if !self.path.exists() {
-            fs::create_dir(&self.path)?;
-        }
+    /// Writes to a temporary file first, so a crash can't leave a partially written file behind.
+    fn write_atomically(&self, key: &str, data_path: &Path, data: &[u8]) -> Result<()> {
+        let tmp_path = self.path.join(format!(".{}{}", key, TMP_SUFFIX));
+        fs::write(&tmp_path, data)?;
+        fs::rename(tmp_path, data_path)?;
         Ok(())
     }
 
-    fn save_content(&self, content: Content) -> Result<()> {
-        let content_data = content.content.ok_or_else(|| {
+    /// Reads content, telling damaged files apart from ones that can't be read right now.
+    pub fn check_content(&self, key: &str) -> Result<Content, ReadError> {
+        self.read_content(key, false).map(|(content, _)| content)
+    }
+
+    /// Temporary files left behind by writes that were interrupted, e.g. by a crash.
+    pub fn list_leftovers(&self) -> Result<Vec<String>> {
+        Ok(fs::read_dir(&self.path)?
+            .filter_map(|entry| entry.ok())
+            .filter(|entry| entry.path().is_file())
+            .map(|entry| entry.file_name().to_string_lossy().to_string())
+            .filter(|name| name.starts_with('.') && name.ends_with(TMP_SUFFIX))
+            .collect())
+    }
+
+    /// Moves content out of the way without deleting it, so it can be inspected manually.
+    pub fn quarantine_content(&self, key: &str) -> Result<PathBuf> {
+        let quarantine_dir = self.path.join(QUARANTINE_DIR);
+        if !quarantine_dir.exists() {
+            fs::create_dir(&quarantine_dir)?;
+        }
+        let quarantine_path = quarantine_dir.join(key);
+        fs::rename(self.path.join(key), &quarantine_path)?;
+        Ok(quarantine_path)
+    }
+
+    fn encode_content(&self, content: &Content) -> Result<Bytes> {
+        let content_data = content.content.as_ref().ok_or_else(|| {
             ErrorInternalServerError("Tried saving content, but there's no content to save")
         })?;
 
-        // Ensure we still have the data directory in case it got deleted for some reason
-        self.initialize()?;
-
         // Pre-compute length so we don't need to re-allocate
         let len = 4 // Version (int)
         + 2 + content.key.len() // Key (ushort string)
@@ -60,12 +183,16 @@ impl StorageBackend for LocalStorage {
         + 1 // Is Modifiable (bool)
         + if content.modifiable { 2 } else { 0 }  // Auth Key (ushort string)
         + 4 // Content Encoding (int string)
+        + 2 // Key ID (ushort)
+        + 4 // Max Views (uint)
+        + 2 + content.password_hash.as_ref().map_or(0, String::len) // Password Hash (ushort string)
         + 4 // Content Length (int)
-        + content_data.len(); // Content
+        + content_data.len() // Content
+        + 4; // Checksum (uint)
         let mut w = DataWriter::new(len);
 
         // Version
-        w.write_int(2);
+        w.write_int(FORMAT_VERSION);
 
         // Key
         w.write_utf(&content.key)?;
@@ -84,49 +211,88 @@ impl StorageBackend for LocalStorage {
 
         // Auth Key
         if content.modifiable {
-            w.write_utf(&content.auth_key.unwrap_or_default())?;
+            w.write_utf(content.auth_key.as_deref().unwrap_or_default())?;
         }
 
         // Content Encoding
         w.write_utf_long(&content.content_encoding)?;
 
-        w.write_int_from_usize(content_data.len())?;
+        // Key ID
+        let key_id = self.keyring.active_key_id();
+        w.write_ushort(key_id);
 
-        w.write_slice(&content_data);
+        // Max Views, kept here too so re-indexing doesn't lift the limit
+        w.write_uint(content.max_views.unwrap_or(0));
 
-        let data_path = self.path.join(content.key);
-        if data_path.exists() {
-            return Err(ErrorInternalServerError("Key already used"));
-        }
+        // Password Hash, same as above
+        w.write_utf(content.password_hash.as_deref().unwrap_or_default())?;
 
-        fs::write(data_path, w.get_data())?;
+        // The header is authenticated alongside the content, so it can't be tampered with either.
+        let encrypted;
+        let content_data = if key_id == crypto::NO_KEY {
+            content_data
+        } else {
+            encrypted = self.keyring.encrypt(w.as_slice(), content_data)?;
+            &encrypted
+        };
 
-        Ok(())
+        w.write_int_from_usize(content_data.len())?;
+
+        w.write_slice(content_data);
+
+        // Checksum
+        let checksum = crc32c::crc32c(w.as_slice());
+        w.write_uint(checksum);
+
+        Ok(w.get_data())
     }
 
-    fn get_content(&self, key: &str, skip_content: bool) -> Result<Content> {
+    /// Reads the content with the given key, along with the ID of the key it was encrypted with.
+    fn read_content(&self, key: &str, skip_content: bool) -> Result<(Content, u16), ReadError> {
+        use ReadError::{Corrupt, Unavailable};
+
         let data_path = self.path.join(key);
         if !data_path.exists() {
-            return Err(ErrorNotFound("Invalid path"));
+            return Err(Unavailable(ErrorNotFound("Invalid path")));
         }
 
         // todo: don't read all file data if we're skipping the content
-        let file_data = fs::read(data_path)?;
+        let file_data = fs::read(data_path).map_err(|err| Unavailable(err.into()))?;
         let mut r = DataReader::new(&file_data);
 
-        let version = r.read_int();
+        let version = r.read_int().map_err(Corrupt)?;
+        // e.g. written by a newer build, which we can't tell apart from a damaged file
+        if !(1..=FORMAT_VERSION).contains(&version) {
+            return Err(Corrupt(ErrorInternalServerError(format!(
+                "Unknown storage format version {}",
+                version
+            ))));
+        }
+
+        if version >= 3 && self.verify_checksums {
+            let (data, checksum) = file_data.split_at(file_data.len() - 4);
+            let expected = DataReader::new(checksum).read_uint().map_err(Corrupt)?;
+            let actual = crc32c::crc32c(data);
+            if actual != expected {
+                error!(
+                    "Checksum mismatch for paste {}! Expected {:08x}, got {:08x}. The file may be corrupted.",
+                    key, expected, actual
+                );
+                return Err(Corrupt(ErrorInternalServerError("Checksum mismatch")));
+            }
+        }
 
-        let key = r.read_utf()?;
+        let key = r.read_utf().map_err(Corrupt)?;
 
-        let content_type = r.read_utf_long()?;
+        let content_type = r.read_utf_long().map_err(Corrupt)?;
 
-        let expiry = r.read_long();
+        let expiry = r.read_long().map_err(Corrupt)?;
         let expiry = if expiry == -1 { None } else { Some(expiry) };
 
-        let last_modified = r.read_long();
-        let modifiable = r.read_bool();
+        let last_modified = r.read_long().map_err(Corrupt)?;
+        let modifiable = r.read_bool().map_err(Corrupt)?;
         let auth_key = if modifiable {
-            Some(r.read_utf()?)
+            Some(r.read_utf().map_err(Corrupt)?)
         } else {
             None
         };
@@ -134,28 +300,107 @@ impl StorageBackend for LocalStorage {
         let content_encoding = if version == 1 {
             ContentEncoding::Gzip.as_str().to_string()
         } else {
-            r.read_utf_long()?
+            r.read_utf_long().map_err(Corrupt)?
         };
 
-        let content_length: usize = r.read_int_as_usize()?;
+        // Added in v3, along with the checksum
+        let (key_id, max_views, password_hash) = if version >= 3 {
+            let key_id = r.read_ushort().map_err(Corrupt)?;
+            let max_views =
+                Some(r.read_uint().map_err(Corrupt)?).filter(|&max_views| max_views > 0);
+            let password_hash =
+                Some(r.read_utf().map_err(Corrupt)?).filter(|hash| !hash.is_empty());
+            (key_id, max_views, password_hash)
+        } else {
+            (crypto::NO_KEY, None, None)
+        };
+        let header_len = file_data.len() - r.buf.len();
+
+        let content_length: usize = r.read_int_as_usize().map_err(Corrupt)?;
+        // Checked first, so a damaged length can't make us allocate up to 2 GiB
+        r.check_remaining(content_length).map_err(Corrupt)?;
         let mut content = vec![0u8; content_length];
-        r.read_fully(&mut content)?;
-
-        Ok(Content {
-            key,
-            content_type,
-            expiry,
-            last_modified,
-            modifiable,
-            auth_key,
-            content_encoding,
-            backend_id: self.backend_id().to_string(),
-            content_length,
-            content: if skip_content { None } else { Some(content) },
-        })
+        r.read_fully(&mut content).map_err(Corrupt)?;
+
+        let (content_length, content) = if key_id == crypto::NO_KEY {
+            (content_length, content)
+        } else if skip_content {
+            (crypto::plaintext_len(content_length), content)
+        } else if !self.keyring.has_key(key_id) {
+            return Err(Unavailable(ErrorInternalServerError(format!(
+                "Unknown encryption key {}",
+                key_id
+            ))));
+        } else {
+            let content = self
+                .keyring
+                .decrypt(key_id, &file_data[..header_len], &content)
+                .map_err(Corrupt)?;
+            (content.len(), content)
+        };
+
+        Ok((
+            Content {
+                key,
+                content_type,
+                expiry,
+                last_modified,
+                modifiable,
+                auth_key,
+                content_encoding,
+                backend_id: self.backend_id().to_string(),
+                content_length,
+                content: if skip_content { None } else { Some(content) },
+                max_views,
+                views: 0,
+                password_hash,
+            },
+            key_id,
+        ))
     }
+}
 
-    fn list_all_content(&self) -> Result<Vec<Content>> {
+impl StorageBackend for LocalStorage {
+    fn backend_id(&self) -> &'static str {
+        "local"
+    }
+
+    fn initialize(&self) -> Result<()> {
+        if !self.path.exists() {
+            fs::create_dir(&self.path)?;
+        }
+        Ok(())
+    }
+
+    fn save_content(&self, content: &Content) -> Result<bool> {
+        // Ensure we still have the data directory in case it got deleted for some reason
+        self.initialize()?;
+
+        let data_path = self.path.join(&content.key);
+        if data_path.exists() {
+            return Ok(false);
+        }
+
+        let data = self.encode_content(content)?;
+        self.write_atomically(&content.key, &data_path, &data)?;
+
+        Ok(true)
+    }
+
+    fn get_content(&self, key: &str, skip_content: bool) -> Result<Content> {
+        Ok(self.read_content(key, skip_content)?.0)
+    }
+
+    fn delete_content(&self, key: &str) -> Result<()> {
+        let data_path = self.path.join(key);
+        if !data_path.exists() {
+            return Err(ErrorNotFound("Invalid path"));
+        }
+        fs::remove_file(data_path)?;
+        Ok(())
+    }
+
+    fn list_keys(&self) -> Result<Vec<String>> {
         Ok(fs::read_dir(&self.path)?
             .filter_map(|x| {
                 if let Ok(path) = x {
@@ -163,13 +408,22 @@ impl StorageBackend for LocalStorage {
                         return path
                             .path()
                             .file_name()
-                            .map(|s| s.to_string_lossy().to_string());
+                            .map(|s| s.to_string_lossy().to_string())
+                            // Skip leftovers from interrupted rewrites
+                            .filter(|s| !s.starts_with('.'));
                     } else {
                         return None;
                     }
                 }
                 None
             })
+            .collect())
+    }
+
+    fn list_all_content(&self) -> Result<Vec<Content>> {
+        Ok(self
+            .list_keys()?
+            .into_iter()
             .filter_map(|key| match self.get_content(&key, true) {
                 Ok(content) => Some(content),
                 Err(err) => {
@@ -180,3 +434,163 @@ impl StorageBackend for LocalStorage {
             .collect())
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::StorageConfig;
+
+    fn keyring(key_id: u16) -> Keyring {
+        Keyring::from_config(&StorageConfig {
+            encryption: true,
+            encryption_key: Some("42".repeat(32)),
+            encryption_key_id: key_id,
+            ..Default::default()
+        })
+        .unwrap()
+    }
+
+    fn content(key: &str) -> Content {
+        Content {
+            content_length: 5,
+            content: Some(b"hello".to_vec()),
+            ..Content::for_tests(key)
+        }
+    }
+
+    #[test]
+    fn encrypted_round_trip_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().to_path_buf(), keyring(1), true);
+        storage.save_content(&content("abc123")).unwrap();
+
+        let raw = fs::read(dir.path().join("abc123")).unwrap();
+        assert!(!raw.windows(5).any(|w| w == b"hello"));
+
+        let read = storage.get_content("abc123", false).unwrap();
+        assert_eq!(read.content.unwrap(), b"hello");
+        assert_eq!(read.content_length, 5);
+        assert_eq!(
+            storage.get_content("abc123", true).unwrap().content_length,
+            5
+        );
+
+        // Tampering with the header must fail authentication, even without checksums
+        let mut tampered = raw.clone();
+        tampered[4 + 2 + 6 + 4 + 10 + 8 + 7] ^= 1; // Last Modified
+        fs::write(dir.path().join("abc123"), tampered).unwrap();
+        let storage = LocalStorage::new(dir.path().to_path_buf(), keyring(1), false);
+        assert!(storage.get_content("abc123", false).is_err());
+    }
+
+    #[test]
+    fn reencrypt_test() {
+        let dir = tempfile::tempdir().unwrap();
+        LocalStorage::new(dir.path().to_path_buf(), Keyring::empty(), true)
+            .save_content(&Content {
+                max_views: Some(3),
+                password_hash: Some("$argon2id$v=19$m=19456,t=2,p=1$abc$def".to_string()),
+                ..content("abc123")
+            })
+            .unwrap();
+
+        let storage = LocalStorage::new(dir.path().to_path_buf(), keyring(2), true);
+        assert!(storage.reencrypt_content("abc123").unwrap());
+        assert!(!storage.reencrypt_content("abc123").unwrap());
+        let read = storage.get_content("abc123", false).unwrap();
+        assert_eq!(read.content.unwrap(), b"hello");
+        assert_eq!(read.max_views, Some(3));
+        assert!(read.password_hash.unwrap().starts_with("$argon2id$"));
+        assert_eq!(storage.list_keys().unwrap(), vec!["abc123".to_string()]);
+    }
+
+    #[test]
+    fn checksum_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let storage = LocalStorage::new(dir.path().to_path_buf(), Keyring::empty(), true);
+        storage.save_content(&content("abc123")).unwrap();
+
+        let mut raw = fs::read(dir.path().join("abc123")).unwrap();
+        let len = raw.len();
+        raw[len - 5] ^= 1; // Last byte of the content
+        fs::write(dir.path().join("abc123"), raw).unwrap();
+
+        assert!(storage.get_content("abc123", false).is_err());
+        let storage = LocalStorage::new(dir.path().to_path_buf(), Keyring::empty(), false);
+        assert_eq!(
+            storage
+                .get_content("abc123", false)
+                .unwrap()
+                .content
+                .unwrap(),
+            b"helln"
+        );
+    }
+
+    #[test]
+    fn truncated_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("abc123");
+        // Without checksums, the reads themselves have to notice
+        let storage = LocalStorage::new(dir.path().to_path_buf(), Keyring::empty(), false);
+        storage.save_content(&content("abc123")).unwrap();
+        let raw = fs::read(&path).unwrap();
+
+        // Everything up to the checksum is needed
+        for len in 0..raw.len() - 4 {
+            fs::write(&path, &raw[..len]).unwrap();
+            assert!(
+                matches!(storage.check_content("abc123"), Err(ReadError::Corrupt(_))),
+                "{} bytes",
+                len
+            );
+            assert!(storage.get_content("abc123", true).is_err());
+        }
+
+        let mut unknown_version = raw.clone();
+        unknown_version[..4].copy_from_slice(&(FORMAT_VERSION + 1).to_be_bytes());
+        fs::write(&path, unknown_version).unwrap();
+        let Err(ReadError::Corrupt(err)) = storage.check_content("abc123") else {
+            panic!("Read a file with an unknown version");
+        };
+        assert!(err.to_string().contains("Unknown storage format version"));
+
+        // The length of the content is right before it
+        let mut huge_length = raw.clone();
+        let length_at = raw.len() - 4 - 5 - 4;
+        huge_length[length_at..length_at + 4].copy_from_slice(&i32::MAX.to_be_bytes());
+        fs::write(&path, huge_length).unwrap();
+        assert!(matches!(
+            storage.check_content("abc123"),
+            Err(ReadError::Corrupt(_))
+        ));
+    }
+
+    #[test]
+    fn read_v2_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut w = DataWriter::new(0);
+        w.write_int(2);
+        w.write_utf("abc123").unwrap();
+        w.write_utf_long("text/plain").unwrap();
+        w.write_long(-1);
+        w.write_long(1721160516802);
+        w.write_bool(false);
+        w.write_utf_long("gzip").unwrap();
+        w.write_int(5);
+        w.write_slice(b"hello");
+        let raw = w.get_data();
+        fs::write(dir.path().join("abc123"), &raw).unwrap();
+
+        let storage = LocalStorage::new(dir.path().to_path_buf(), keyring(1), true);
+        let read = storage.get_content("abc123", false).unwrap();
+        assert_eq!(read.content.unwrap(), b"hello");
+        assert_eq!(read.content_encoding, "gzip");
+
+        // There's no checksum to catch truncation in v2
+        for len in 0..raw.len() {
+            fs::write(dir.path().join("abc123"), &raw[..len]).unwrap();
+            assert!(storage.get_content("abc123", true).is_err());
+        }
+    }
+}
@@ -0,0 +1,153 @@
+use std::{
+    future::Future,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+
+use actix_web::rt::{self, task::JoinHandle, time::Interval};
+use log::{debug, error, info};
+use tokio::sync::watch;
+
+use crate::{
+    db,
+    stats::PendingStats,
+    storage::{self, StorageBackend},
+};
+
+/// Background tasks, which are told to stop on shutdown so they can finish what they're doing.
+pub struct Tasks {
+    shutdown: watch::Sender<bool>,
+    handles: Vec<JoinHandle<()>>,
+}
+
+impl Default for Tasks {
+    fn default() -> Self {
+        Self {
+            shutdown: watch::channel(false).0,
+            handles: Vec::new(),
+        }
+    }
+}
+
+impl Tasks {
+    /// Spawns a task, which should return once `shutdown` changes.
+    pub fn spawn<F, Fut>(&mut self, task: F)
+    where
+        F: FnOnce(watch::Receiver<bool>) -> Fut,
+        Fut: Future<Output = ()> + 'static,
+    {
+        self.handles
+            .push(rt::spawn(task(self.shutdown.subscribe())));
+    }
+
+    /// Tells all tasks to stop, and waits for them to do so.
+    pub async fn shutdown(self) {
+        let _ = self.shutdown.send(true);
+        for handle in self.handles {
+            if let Err(err) = handle.await {
+                error!("Background task failed: {}", err);
+            }
+        }
+    }
+}
+
+/// Waits for the next tick, returning false if shutting down instead.
+async fn next_tick(interval: &mut Interval, shutdown: &mut watch::Receiver<bool>) -> bool {
+    tokio::select! {
+        _ = interval.tick() => !*shutdown.borrow(),
+        _ = shutdown.changed() => false,
+    }
+}
+
+/// Periodically deletes expired content and content without views left,
+/// from both the database and storage.
+/// Content that hasn't been viewed for `inactivity_expiry` is deleted too, if it's given.
+pub fn spawn_expiry_sweep(
+    tasks: &mut Tasks,
+    metadata: db::Metadata,
+    storage: Arc<dyn StorageBackend + Sync + Send>,
+    interval: Duration,
+    inactivity_expiry: Option<Duration>,
+) {
+    tasks.spawn(|mut shutdown| async move {
+        let mut interval = rt::time::interval(interval);
+        while next_tick(&mut interval, &mut shutdown).await {
+            let now = match SystemTime::now().duration_since(SystemTime::UNIX_EPOCH) {
+                Ok(now) => now.as_millis() as i64,
+                Err(err) => {
+                    error!("Failed to get the current time: {}", err);
+                    continue;
+                }
+            };
+
+            match db::delete_expired(&metadata, now).await {
+                Ok(expired) => delete_files(&storage, &expired, "expired").await,
+                Err(err) => error!("Failed to delete expired content: {}", err),
+            }
+
+            if let Some(inactivity_expiry) = inactivity_expiry {
+                let before = now - inactivity_expiry.as_millis() as i64;
+                match db::delete_inactive(&metadata, before).await {
+                    Ok(inactive) => delete_files(&storage, &inactive, "inactive").await,
+                    Err(err) => error!("Failed to delete inactive content: {}", err),
+                }
+            }
+        }
+    });
+}
+
+/// Deletes the files of content that was just deleted from the database.
+async fn delete_files(
+    storage: &Arc<dyn StorageBackend + Sync + Send>,
+    keys: &[String],
+    reason: &str,
+) {
+    if keys.is_empty() {
+        return;
+    }
+    for key in keys {
+        match storage::delete_content(storage, key.clone()).await {
+            Ok(_) => debug!("Deleted {} paste {}", reason, key),
+            Err(err) => error!("Failed to delete {} paste {}: {}", reason, key, err),
+        }
+    }
+    info!("Deleted {} {} pastes", keys.len(), reason);
+}
+
+/// Periodically writes pending stats to the database, and once more on shutdown.
+pub fn spawn_stats_flush(
+    tasks: &mut Tasks,
+    metadata: db::Metadata,
+    stats: Arc<PendingStats>,
+    interval: Duration,
+) {
+    tasks.spawn(|mut shutdown| async move {
+        let mut interval = rt::time::interval(interval);
+        // The first tick completes immediately, and there's nothing to flush at startup
+        interval.tick().await;
+        loop {
+            let running = next_tick(&mut interval, &mut shutdown).await;
+            if let Err(err) = stats.flush(&metadata).await {
+                error!("Failed to write stats: {}", err);
+            }
+            if !running {
+                break;
+            }
+        }
+    });
+}
+
+/// Periodically flushes the metadata store, e.g. checkpointing SQLite's write-ahead log.
+pub fn spawn_checkpoint(tasks: &mut Tasks, metadata: db::Metadata, interval: Duration) {
+    tasks.spawn(|mut shutdown| async move {
+        let mut interval = rt::time::interval(interval);
+        // The first tick completes immediately, and there's nothing to checkpoint at startup
+        interval.tick().await;
+        while next_tick(&mut interval, &mut shutdown).await {
+            match db::checkpoint(&metadata).await {
+                Ok(_) => debug!("Checkpointed {} database", metadata.backend_id()),
+                Err(err) => error!("Failed to checkpoint database: {}", err),
+            }
+        }
+    });
+}
@@ -0,0 +1,334 @@
+use std::future::Future;
+
+use actix_web::{
+    body::MessageBody,
+    dev::{ServiceRequest, ServiceResponse},
+    http::header::HeaderMap,
+    middleware::Next,
+    Error, HttpMessage,
+};
+use anyhow::Result;
+use opentelemetry::{
+    global::{self, BoxedTracer},
+    propagation::Extractor,
+    trace::{get_active_span, FutureExt, SpanKind, Status, TraceContextExt, Tracer},
+    Context, KeyValue,
+};
+
+use crate::{access_log::RequestId, config::TelemetryConfig};
+
+#[cfg(feature = "otel")]
+static PROVIDER: std::sync::OnceLock<opentelemetry_sdk::trace::SdkTracerProvider> =
+    std::sync::OnceLock::new();
+
+/// Starts exporting traces if an OTLP endpoint is configured.
+/// Until then, spans are created by a tracer that doesn't do anything.
+#[cfg(feature = "otel")]
+pub fn init(config: &TelemetryConfig) -> Result<()> {
+    use anyhow::bail;
+    use log::info;
+    use opentelemetry_otlp::{SpanExporter, WithExportConfig};
+    use opentelemetry_sdk::{
+        propagation::TraceContextPropagator,
+        trace::{Sampler, SdkTracerProvider},
+        Resource,
+    };
+
+    let Some(endpoint) = &config.otlp_endpoint else {
+        return Ok(());
+    };
+    let exporter = match SpanExporter::builder()
+        .with_http()
+        .with_endpoint(endpoint)
+        .build()
+    {
+        Ok(exporter) => exporter,
+        Err(err) => bail!("Failed to set up the OTLP exporter: {}", err),
+    };
+
+    let provider = SdkTracerProvider::builder()
+        .with_batch_exporter(exporter)
+        // Follow the caller if it's already tracing, so traces aren't left with holes
+        .with_sampler(Sampler::ParentBased(Box::new(Sampler::TraceIdRatioBased(
+            config.sample_ratio,
+        ))))
+        .with_resource(
+            Resource::builder()
+                .with_service_name(config.service_name.clone())
+                .build(),
+        )
+        .build();
+    global::set_text_map_propagator(TraceContextPropagator::new());
+    global::set_tracer_provider(provider.clone());
+    let _ = PROVIDER.set(provider);
+
+    info!("Exporting traces to {}", endpoint);
+    Ok(())
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn init(config: &TelemetryConfig) -> Result<()> {
+    if config.otlp_endpoint.is_some() {
+        anyhow::bail!(
+            "bitbin was built without OpenTelemetry support! Rebuild it with `--features otel`."
+        );
+    }
+    Ok(())
+}
+
+/// Exports any spans that haven't been sent yet.
+#[cfg(feature = "otel")]
+pub fn shutdown() {
+    if let Some(provider) = PROVIDER.get() {
+        if let Err(err) = provider.shutdown() {
+            log::error!("Failed to export the remaining traces: {}", err);
+        }
+    }
+}
+
+#[cfg(not(feature = "otel"))]
+pub fn shutdown() {}
+
+fn tracer() -> BoxedTracer {
+    global::tracer("bitbin")
+}
+
+/// Traces every request, continuing the caller's trace if it sent a `traceparent` header.
+pub async fn trace_request(
+    req: ServiceRequest,
+    next: Next<impl MessageBody + 'static>,
+) -> Result<ServiceResponse<impl MessageBody>, Error> {
+    let parent = global::get_text_map_propagator(|propagator| {
+        propagator.extract(&HeaderExtractor(req.headers()))
+    });
+
+    let method = req.method().to_string();
+    let mut attributes = vec![
+        KeyValue::new("http.request.method", method.clone()),
+        KeyValue::new("url.path", req.path().to_string()),
+    ];
+    if let Some(addr) = req.peer_addr() {
+        attributes.push(KeyValue::new("client.address", addr.ip().to_string()));
+    }
+    if let Some(id) = req.extensions().get::<RequestId>() {
+        attributes.push(KeyValue::new("bitbin.request_id", id.0.clone()));
+    }
+    let tracer = tracer();
+    // Renamed to include the route once it's known
+    let span = tracer
+        .span_builder(method.clone())
+        .with_kind(SpanKind::Server)
+        .with_attributes(attributes)
+        .start_with_context(&tracer, &parent);
+    let cx = parent.with_span(span);
+
+    let res = next.call(req).with_context(cx.clone()).await;
+
+    let span = cx.span();
+    match &res {
+        Ok(res) => {
+            if let Some(route) = res.request().match_pattern() {
+                span.update_name(format!("{} {}", method, route));
+                span.set_attribute(KeyValue::new("http.route", route));
+            }
+            let status = res.status();
+            span.set_attribute(KeyValue::new(
+                "http.response.status_code",
+                i64::from(status.as_u16()),
+            ));
+            if status.is_server_error() {
+                span.set_status(Status::error(status.to_string()));
+            }
+        }
+        Err(err) => span.set_status(Status::error(err.to_string())),
+    }
+    res
+}
+
+/// Runs `fut` in a child span of the current one.
+pub async fn traced<F: Future>(name: &'static str, fut: F) -> F::Output {
+    fut.with_context(child_context(name, Vec::new())).await
+}
+
+/// Like [`traced`], with the key of the content `fut` works on.
+pub async fn traced_key<F: Future>(name: &'static str, key: &str, fut: F) -> F::Output {
+    fut.with_context(child_context(name, vec![key_attribute(key)]))
+        .await
+}
+
+/// Runs `f` in a child span of the current one, with the key of the content it works on.
+pub fn traced_sync_key<T>(name: &'static str, key: &str, f: impl FnOnce() -> T) -> T {
+    let _guard = child_context(name, vec![key_attribute(key)]).attach();
+    f()
+}
+
+/// Wraps `f` to run in the current context, wherever it runs, e.g. on the blocking thread pool.
+/// Spans it starts are children of the current span, instead of starting traces of their own.
+pub fn in_current_context<T>(f: impl FnOnce() -> T + Send) -> impl FnOnce() -> T + Send {
+    let cx = Context::current();
+    move || {
+        let _guard = cx.attach();
+        f()
+    }
+}
+
+/// Adds the key of the content being handled to the request's span.
+pub fn record_key(key: &str) {
+    get_active_span(|span| span.set_attribute(key_attribute(key)));
+}
+
+fn key_attribute(key: &str) -> KeyValue {
+    KeyValue::new("bitbin.key", key.to_string())
+}
+
+fn child_context(name: &'static str, attributes: Vec<KeyValue>) -> Context {
+    let tracer = tracer();
+    let span = tracer
+        .span_builder(name)
+        .with_attributes(attributes)
+        .start(&tracer);
+    Context::current_with_span(span)
+}
+
+struct HeaderExtractor<'a>(&'a HeaderMap);
+
+impl Extractor for HeaderExtractor<'_> {
+    fn get(&self, key: &str) -> Option<&str> {
+        self.0.get(key).and_then(|value| value.to_str().ok())
+    }
+
+    fn keys(&self) -> Vec<&str> {
+        self.0.keys().map(|name| name.as_str()).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, get, post, State};
+    use actix_web::{
+        http::header::{self, HeaderName, HeaderValue},
+        middleware,
+        test::{self, TestRequest},
+        web::Data,
+        App,
+    };
+    use opentelemetry::{propagation::TextMapPropagator, trace::SpanId, Value};
+    use opentelemetry_sdk::{
+        error::OTelSdkResult,
+        propagation::TraceContextPropagator,
+        trace::{SdkTracerProvider, SpanData, SpanExporter},
+    };
+    use std::sync::{Arc, Mutex};
+
+    /// Keeps exported spans around, instead of sending them anywhere.
+    #[derive(Clone, Debug, Default)]
+    struct StubExporter(Arc<Mutex<Vec<SpanData>>>);
+
+    impl SpanExporter for StubExporter {
+        async fn export(&self, batch: Vec<SpanData>) -> OTelSdkResult {
+            self.0.lock().unwrap().extend(batch);
+            Ok(())
+        }
+    }
+
+    #[actix_web::test]
+    async fn spans_test() {
+        let exporter = StubExporter::default();
+        global::set_tracer_provider(
+            SdkTracerProvider::builder()
+                .with_simple_exporter(exporter.clone())
+                .build(),
+        );
+
+        let dir = tempfile::tempdir().unwrap();
+        let state = Data::new(State::for_tests(dir.path(), Config::default()).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(state)
+                .wrap(middleware::from_fn(trace_request))
+                .service(post::post)
+                .service(get::get),
+        )
+        .await;
+
+        let req = TestRequest::post()
+            .uri("/post")
+            .insert_header((header::CONTENT_TYPE, "text/plain"))
+            .set_payload("traced")
+            .to_request();
+        let res = test::call_service(&app, req).await;
+        let key = res
+            .headers()
+            .get(header::LOCATION)
+            .unwrap()
+            .to_str()
+            .unwrap()
+            .to_string();
+        let req = TestRequest::get().uri(&format!("/{}", key)).to_request();
+        assert!(test::call_service(&app, req).await.status().is_success());
+
+        // Other tests may be tracing at the same time, so only look at this key's spans
+        let spans: Vec<SpanData> = exporter
+            .0
+            .lock()
+            .unwrap()
+            .iter()
+            .filter(|span| {
+                span.attributes.iter().any(|attribute| {
+                    attribute.key.as_str() == "bitbin.key"
+                        && attribute.value == Value::from(key.clone())
+                })
+            })
+            .cloned()
+            .collect();
+        let span = |name: &str| {
+            spans
+                .iter()
+                .find(|span| span.name == name)
+                .unwrap_or_else(|| panic!("No {} span with the key", name))
+        };
+
+        for (request, children) in [
+            (
+                "POST /post",
+                &["db.save_content_info", "storage.save_content"][..],
+            ),
+            (
+                "GET /{key}",
+                &["db.get_content_info", "storage.get_content"][..],
+            ),
+        ] {
+            let request = span(request);
+            assert_eq!(request.parent_span_id, SpanId::INVALID);
+            for child in children {
+                let child = span(child);
+                assert_eq!(child.parent_span_id, request.span_context.span_id());
+                assert_eq!(
+                    child.span_context.trace_id(),
+                    request.span_context.trace_id()
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn traceparent_test() {
+        let mut headers = HeaderMap::new();
+        headers.insert(
+            HeaderName::from_static("traceparent"),
+            HeaderValue::from_static("00-4bf92f3577b34da6a3ce929d0e0e4736-00f067aa0ba902b7-01"),
+        );
+
+        let cx = TraceContextPropagator::new().extract(&HeaderExtractor(&headers));
+        let span = cx.span();
+        let parent = span.span_context();
+        assert!(parent.is_remote());
+        assert!(parent.is_sampled());
+        assert_eq!(
+            parent.trace_id().to_string(),
+            "4bf92f3577b34da6a3ce929d0e0e4736"
+        );
+        assert_eq!(parent.span_id().to_string(), "00f067aa0ba902b7");
+    }
+}
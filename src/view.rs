@@ -0,0 +1,187 @@
+use actix_web::{
+    error::ErrorNotFound,
+    get,
+    http::{header, StatusCode},
+    post,
+    web::{Data, Form, Path},
+    Error, HttpRequest, HttpResponse, Responder,
+};
+use serde::Deserialize;
+
+use crate::{get, password, telemetry, State};
+
+/// Only lets the form post back to us, since it's the one page we serve that isn't sandboxed.
+const CONTENT_SECURITY_POLICY: &str = "default-src 'none'; form-action 'self'";
+const TEXT_HTML: &str = "text/html; charset=utf-8";
+
+#[derive(Deserialize)]
+pub struct PasswordForm {
+    password: String,
+}
+
+/// Asks for the password of protected content in a form, for browsers.
+/// Content without a password is sent to the regular URL instead.
+#[get("/view/{key}")]
+pub async fn view(state: Data<State>, key: Path<String>) -> Result<impl Responder, Error> {
+    let key = validate(&key)?;
+    let content = get::find(&state, key).await?;
+    if content.password_hash.is_none() {
+        return Ok(HttpResponse::SeeOther()
+            .insert_header((header::LOCATION, format!("/{}", key)))
+            .finish());
+    }
+    Ok(prompt(StatusCode::OK, None))
+}
+
+/// Responds with the content if the posted password is right, or asks again.
+#[post("/view/{key}")]
+pub async fn view_with_password(
+    state: Data<State>,
+    req: HttpRequest,
+    key: Path<String>,
+    form: Form<PasswordForm>,
+) -> Result<impl Responder, Error> {
+    let key = validate(&key)?;
+    let content = get::find(&state, key).await?;
+    if let Some(hash) = &content.password_hash {
+        let config = state.config.load();
+        let password = form.into_inner().password;
+        let valid = password::verify(
+            &req,
+            &state.password_failures,
+            &config.content,
+            key,
+            hash,
+            password,
+        )
+        .await;
+        match valid {
+            Ok(true) => {}
+            Ok(false) => return Ok(prompt(StatusCode::UNAUTHORIZED, Some("Wrong password"))),
+            Err(err) if err.as_response_error().status_code() == StatusCode::TOO_MANY_REQUESTS => {
+                return Ok(prompt(
+                    StatusCode::TOO_MANY_REQUESTS,
+                    Some("Too many wrong passwords, try again later"),
+                ))
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    get::serve(&state, &req, key, content).await
+}
+
+fn validate(key: &str) -> Result<&str, Error> {
+    // This is responsible for preventing path-traversal!
+    if !get::validate_path(key) {
+        return Err(ErrorNotFound("Invalid path"));
+    }
+    telemetry::record_key(key);
+    Ok(key)
+}
+
+/// The password form, which posts back to the URL it was loaded from.
+fn prompt(status: StatusCode, message: Option<&str>) -> HttpResponse {
+    let message = message
+        .map(|message| format!("<p>{}</p>", message))
+        .unwrap_or_default();
+    HttpResponse::build(status)
+        .insert_header((header::CONTENT_TYPE, TEXT_HTML))
+        .insert_header((header::CONTENT_SECURITY_POLICY, CONTENT_SECURITY_POLICY))
+        .insert_header((header::CACHE_CONTROL, "no-store"))
+        .body(format!(
+            "<!doctype html>
+<html>
+<head><meta charset=\"utf-8\"><title>Password required</title></head>
+<body>
+{}<form method=\"post\">
+<label>Password <input type=\"password\" name=\"password\" autofocus required></label>
+<button type=\"submit\">View</button>
+</form>
+</body>
+</html>
+",
+            message
+        ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{config::Config, post};
+    use actix_web::{
+        test::{self, TestRequest},
+        App,
+    };
+
+    #[actix_web::test]
+    async fn view_test() {
+        let dir = tempfile::tempdir().unwrap();
+        let state = Data::new(State::for_tests(dir.path(), Config::default()).await);
+        let app = test::init_service(
+            App::new()
+                .app_data(state.clone())
+                .service(post::post)
+                .service(view)
+                .service(view_with_password),
+        )
+        .await;
+        let upload = |password: Option<&'static str>| {
+            let mut req = TestRequest::post()
+                .uri("/post")
+                .insert_header((header::CONTENT_TYPE, "text/plain"))
+                .set_payload("for your eyes only");
+            if let Some(password) = password {
+                req = req.insert_header((password::PASSWORD_HEADER, password));
+            }
+            req.to_request()
+        };
+        let location = |res: &actix_web::dev::ServiceResponse| {
+            let location = res.headers().get(header::LOCATION).unwrap();
+            location.to_str().unwrap().to_string()
+        };
+
+        let res = test::call_service(&app, upload(None)).await;
+        let key = location(&res);
+        let req = TestRequest::get().uri(&format!("/view/{}", key));
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::SEE_OTHER);
+        assert_eq!(location(&res), format!("/{}", key));
+
+        let res = test::call_service(&app, upload(Some("hunter2"))).await;
+        let key = location(&res);
+        let uri = format!("/view/{}", key);
+        let res = test::call_service(&app, TestRequest::get().uri(&uri).to_request()).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        let body = test::read_body(res).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("name=\"password\""));
+
+        let submit = |password| {
+            TestRequest::post()
+                .uri(&uri)
+                .set_form(std::collections::HashMap::from([("password", password)]))
+                .to_request()
+        };
+        let res = test::call_service(&app, submit("wrong")).await;
+        assert_eq!(res.status(), StatusCode::UNAUTHORIZED);
+        // Browsers would show their own prompt instead of the form
+        assert!(res.headers().get(header::WWW_AUTHENTICATE).is_none());
+        let body = test::read_body(res).await;
+        assert!(std::str::from_utf8(&body)
+            .unwrap()
+            .contains("Wrong password"));
+
+        let res = test::call_service(&app, submit("hunter2")).await;
+        assert_eq!(res.status(), StatusCode::OK);
+        assert_eq!(
+            res.headers().get(header::CACHE_CONTROL).unwrap(),
+            "no-store"
+        );
+        assert_eq!(test::read_body(res).await, "for your eyes only");
+
+        let req = TestRequest::get().uri("/view/..abc");
+        let res = test::call_service(&app, req.to_request()).await;
+        assert_eq!(res.status(), StatusCode::NOT_FOUND);
+    }
+}